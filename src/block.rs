@@ -0,0 +1,792 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+
+use crate::fsync::FSyncManager;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// A way of storing the contents of files as blocks within the files of
+/// another manager.
+pub trait Format: Debug + Clone + Send + Sync + 'static {
+    /// The contents of an open file.
+    type Blocks<F: File>: Blocks<File = F>;
+
+    /// Returns the contents stored in `file`. The file must not be accessed
+    /// until it is used, as it may be a directory that is only being opened
+    /// to be synced.
+    fn blocks<F: File>(&self, file: F) -> Self::Blocks<F>;
+}
+
+/// The contents of a file, stored as a sequence of blocks in another file.
+///
+/// Every block except the last contains [`block_size()`](Self::block_size)
+/// bytes of contents.
+pub trait Blocks: Debug + Send + 'static {
+    /// The file the blocks are stored in.
+    type File: File;
+
+    /// Returns the file the blocks are stored in.
+    fn file(&mut self) -> &mut Self::File;
+
+    /// Returns the number of bytes of contents in each block.
+    fn block_size(&self) -> usize;
+
+    /// Returns the length of the contents.
+    fn len(&mut self) -> io::Result<u64>;
+
+    /// Returns true if there are no contents.
+    fn is_empty(&mut self) -> io::Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Returns the contents of the block at `index`, which is empty if the
+    /// block is beyond the end of the contents.
+    fn read_block(&mut self, index: u64) -> io::Result<Vec<u8>>;
+
+    /// Stores `contents` as the block at `index`. The contents must already
+    /// extend to the start of the block.
+    fn write_block(&mut self, index: u64, contents: &[u8]) -> io::Result<()>;
+
+    /// Changes the length of the contents, filling any new bytes with zeros.
+    fn set_len(&mut self, new_length: u64) -> io::Result<()>;
+}
+
+/// A [`FileManager`] that stores each file of another manager as blocks
+/// using the format `T`, such as a
+/// [`ChecksummedFileManager`](crate::checksummed::ChecksummedFileManager).
+///
+/// All handles to the same path, including handles opened by clones of this
+/// manager, share the file's state, so they always observe each other's
+/// changes. Files must not be modified through another manager while they
+/// are open.
+///
+/// The most recently accessed block of each open file is kept decoded, and
+/// writes to it are buffered. Buffered writes are stored when another block
+/// is accessed, when the file is flushed, synced or resized, and when its
+/// last handle is dropped. Errors storing them when the file is dropped are
+/// ignored, so call [`Write::flush()`] or [`File::sync_all()`] to observe
+/// them.
+#[derive(Clone, Debug)]
+pub struct BlockFileManager<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    inner: M,
+    format: T,
+    open_files: Arc<Mutex<OpenFiles<M, T>>>,
+    fsyncs: FSyncManager<Self>,
+}
+
+impl<M, T> BlockFileManager<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    /// Returns a manager that stores the files of `inner` using `format`.
+    pub fn with_format(inner: M, format: T) -> Self {
+        Self {
+            inner,
+            format,
+            open_files: Arc::default(),
+            fsyncs: FSyncManager::default(),
+        }
+    }
+
+    /// Returns the wrapped manager.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Rewrites the file at `path` using `compact_into`, and returns the
+    /// number of bytes reclaimed. See [`OpenFile::compact()`].
+    #[cfg(feature = "compression")]
+    pub(crate) fn compact_file(
+        &self,
+        path: &PathId,
+        compact_into: impl FnOnce(&mut T::Blocks<M::File>, &mut M::File) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        let file = self.open(path, OpenOptions::new().write(true))?;
+        let reclaimed = file.lock().compact(&self.inner, &self.format, compact_into);
+        reclaimed
+    }
+
+    /// Calls `change` while every open file at or beneath `path` is locked,
+    /// and then moves the open files to `destination`. If `destination` is
+    /// `None`, the files are detached from their paths instead.
+    ///
+    /// Files that are open at or beneath `destination` are detached, as
+    /// `change` replaces them.
+    fn relocate(
+        &self,
+        path: &PathId,
+        destination: Option<&PathId>,
+        change: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut open_files = lock(&self.open_files);
+        let affected = open_files
+            .iter()
+            .filter(|(open_path, _)| {
+                open_path.starts_with(&**path)
+                    || destination.is_some_and(|destination| open_path.starts_with(&**destination))
+            })
+            .filter_map(|(open_path, state)| Some((open_path.clone(), state.upgrade()?)))
+            .collect::<Vec<_>>();
+        let mut locked = affected
+            .iter()
+            .map(|(open_path, state)| (open_path, lock(state)))
+            .collect::<Vec<_>>();
+        change()?;
+
+        let mut moved = Vec::new();
+        for (open_path, state) in &mut locked {
+            let entry = open_files.remove(*open_path);
+            state.path = match (destination, open_path.strip_prefix(&**path)) {
+                (Some(destination), Ok(relative)) if relative.as_os_str().is_empty() => {
+                    Some(destination.clone())
+                }
+                (Some(destination), Ok(relative)) => Some(PathId::from(destination.join(relative))),
+                _ => None,
+            };
+            if let (Some(new_path), Some(entry)) = (&state.path, entry) {
+                moved.push((new_path.clone(), entry));
+            }
+        }
+        open_files.extend(moved);
+        Ok(())
+    }
+}
+
+impl<M, T> FileManager for BlockFileManager<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    type File = BlockFile<M, T>;
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        let writable = options.write;
+        // Writing to part of a block requires reading the rest of it. The
+        // file is always opened, so that the wrapped manager can reject it.
+        let file = self.inner.open(path, options.read(true))?;
+        let mut open_files = lock(&self.open_files);
+        let state = match open_files.get(path).and_then(Weak::upgrade) {
+            Some(state) => {
+                let mut open_file = lock(&state);
+                if writable && !open_file.writable {
+                    // The existing handle can't be written to.
+                    *open_file.blocks.file() = file;
+                    open_file.writable = true;
+                }
+                drop(open_file);
+                state
+            }
+            None => {
+                let state = Arc::new(Mutex::new(OpenFile {
+                    blocks: self.format.blocks(file),
+                    cached: None,
+                    length: None,
+                    path: Some(path.clone()),
+                    writable,
+                }));
+                open_files.retain(|_, state| state.strong_count() > 0);
+                open_files.insert(path.clone(), Arc::downgrade(&state));
+                state
+            }
+        };
+        drop(open_files);
+
+        Ok(BlockFile {
+            state,
+            position: Arc::default(),
+            path: path.clone(),
+            writable,
+        })
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.relocate(path, None, || self.inner.remove_dir_all(path))
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        self.relocate(path, None, || self.inner.remove_file(path))
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        self.relocate(from, Some(&to), || self.inner.rename(from, to.clone()))
+    }
+
+    fn new_fsync_batch(&self) -> io::Result<crate::FSyncBatch<Self>> {
+        Ok(self.fsyncs.new_batch()?)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.fsyncs.shutdown()?;
+        Ok(())
+    }
+
+    fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        self.inner.list(path)
+    }
+}
+
+/// The state of each open file, by its current path.
+type OpenFiles<M, T> = HashMap<PathId, Weak<Mutex<OpenFile<M, T>>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The state of a file, shared by all of its handles.
+#[derive(Debug)]
+struct OpenFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    blocks: T::Blocks<M::File>,
+    /// The most recently accessed block, which may have changes that haven't
+    /// been stored yet.
+    cached: Option<CachedBlock>,
+    /// The length of the contents, including the changes to `cached`, once
+    /// it is known.
+    length: Option<u64>,
+    /// The file's current path, or `None` if it has been removed.
+    path: Option<PathId>,
+    /// Whether the underlying file was opened for writing.
+    writable: bool,
+}
+
+/// A decoded block of an open file.
+#[derive(Debug)]
+struct CachedBlock {
+    index: u64,
+    contents: Vec<u8>,
+    /// Whether `contents` differs from the stored block.
+    dirty: bool,
+}
+
+impl<M, T> OpenFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    fn len(&mut self) -> io::Result<u64> {
+        match self.length {
+            Some(length) => Ok(length),
+            None => {
+                let length = self.blocks.len()?;
+                self.length = Some(length);
+                Ok(length)
+            }
+        }
+    }
+
+    /// Stores the cached block, if it has been changed.
+    fn store(&mut self) -> io::Result<()> {
+        if let Some(cached) = &mut self.cached {
+            if cached.dirty {
+                self.blocks.write_block(cached.index, &cached.contents)?;
+                cached.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the block at `index`, replacing the cached block with it.
+    fn block(&mut self, index: u64) -> io::Result<&mut CachedBlock> {
+        if self
+            .cached
+            .as_ref()
+            .is_none_or(|cached| cached.index != index)
+        {
+            self.store()?;
+            let contents = self.blocks.read_block(index)?;
+            self.cached = Some(CachedBlock {
+                index,
+                contents,
+                dirty: false,
+            });
+        }
+        Ok(self.cached.as_mut().expect("block was just cached"))
+    }
+
+    fn set_len(&mut self, new_length: u64) -> io::Result<()> {
+        self.store()?;
+        self.cached = None;
+        self.length = None;
+        self.blocks.set_len(new_length)?;
+        self.length = Some(new_length);
+        Ok(())
+    }
+
+    /// Stores any buffered changes and syncs the underlying file.
+    fn sync(&mut self, all: bool) -> io::Result<()> {
+        self.store()?;
+        if all {
+            self.blocks.file().sync_all()
+        } else {
+            self.blocks.file().sync_data()
+        }
+    }
+
+    /// Rewrites the underlying file by calling `compact_into` with its blocks
+    /// and an empty file, and returns the number of bytes reclaimed.
+    ///
+    /// The compacted file is written and synced next to the original, and
+    /// then renamed over it, so a crash leaves either version intact. Fails
+    /// with [`io::ErrorKind::AlreadyExists`] if something already exists
+    /// where the compacted file would be written.
+    #[cfg(feature = "compression")]
+    fn compact(
+        &mut self,
+        inner: &M,
+        format: &T,
+        compact_into: impl FnOnce(&mut T::Blocks<M::File>, &mut M::File) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        let Some(path) = self.path.clone() else {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        };
+        self.store()?;
+        let original_length = self.blocks.file().len()?;
+
+        let mut compacted_path = path.to_path_buf().into_os_string();
+        compacted_path.push(".compacting");
+        let compacted_path = PathId::from(std::path::PathBuf::from(compacted_path));
+        if inner.exists(&compacted_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", compacted_path.display()),
+            ));
+        }
+        let mut compacted = inner.open(
+            &compacted_path,
+            OpenOptions::new().read(true).write(true).create(true),
+        )?;
+        let written = compact_into(&mut self.blocks, &mut compacted)
+            .and_then(|()| compacted.sync_all())
+            .and_then(|()| compacted.len())
+            .and_then(|length| {
+                inner.rename(&compacted_path, path.clone())?;
+                Ok(length)
+            });
+        let compacted_length = match written {
+            Ok(length) => length,
+            Err(err) => {
+                // Don't leave a partially written file behind.
+                let _ = inner.remove_file(&compacted_path);
+                return Err(err);
+            }
+        };
+
+        // The compacted file is reopened so that it is accessed using its
+        // new path, but the existing handle refers to the same file.
+        let file = inner
+            .open(&path, OpenOptions::new().read(true).write(true))
+            .unwrap_or(compacted);
+        self.blocks = format.blocks(file);
+        self.writable = true;
+        if let Some(parent) = path.parent() {
+            inner.sync_all(&parent)?;
+        }
+        Ok(original_length.saturating_sub(compacted_length))
+    }
+
+    /// Reads from the block containing `position`.
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || position >= self.len()? {
+            return Ok(0);
+        }
+
+        let block_size = self.blocks.block_size() as u64;
+        let block = self.block(position / block_size)?;
+        let offset = (position % block_size) as usize;
+        let bytes_read = buf.len().min(block.contents.len() - offset);
+        buf[..bytes_read].copy_from_slice(&block.contents[offset..offset + bytes_read]);
+        Ok(bytes_read)
+    }
+
+    /// Writes to the block containing `position`. The change is only stored
+    /// once another block is accessed, or the file is flushed.
+    fn write_at(&mut self, position: u64, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let length = self.len()?;
+        if position > length {
+            // Writing beyond the end of the file fills the gap with zeros.
+            self.set_len(position)?;
+        }
+
+        let block_size = self.blocks.block_size();
+        let index = position / block_size as u64;
+        let offset = (position % block_size as u64) as usize;
+        let block = self.block(index)?;
+        let bytes_written = buf.len().min(block_size - offset);
+        if block.contents.len() < offset + bytes_written {
+            block.contents.resize(offset + bytes_written, 0);
+        }
+        block.contents[offset..offset + bytes_written].copy_from_slice(&buf[..bytes_written]);
+        block.dirty = true;
+        let end = index * block_size as u64 + block.contents.len() as u64;
+        self.length = Some(length.max(position).max(end));
+        Ok(bytes_written)
+    }
+}
+
+impl<M, T> Drop for OpenFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    fn drop(&mut self) {
+        // Like `BufWriter`, errors can't be reported while dropping.
+        let _ = self.store();
+    }
+}
+
+/// A file opened by a [`BlockFileManager`].
+///
+/// Clones created using [`File::try_clone()`] share the position, like clones
+/// of a [`std::fs::File`].
+#[derive(Debug)]
+pub struct BlockFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    state: Arc<Mutex<OpenFile<M, T>>>,
+    position: Arc<Mutex<u64>>,
+    path: PathId,
+    writable: bool,
+}
+
+impl<M, T> BlockFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    fn lock(&self) -> MutexGuard<'_, OpenFile<M, T>> {
+        lock(&self.state)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file wasn't opened for writing",
+            ))
+        }
+    }
+}
+
+impl<M, T> File for BlockFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    type Manager = BlockFileManager<M, T>;
+
+    fn path(&self) -> &PathId {
+        &self.path
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.lock().sync(true)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.lock().sync(false)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.lock().len()
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.lock().set_len(new_length)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            state: self.state.clone(),
+            position: self.position.clone(),
+            path: self.path.clone(),
+            writable: self.writable,
+        })
+    }
+}
+
+impl<M, T> Read for BlockFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut position = lock(&self.position);
+        let bytes_read = self.lock().read_at(*position, buf)?;
+        *position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<M, T> Write for BlockFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        let mut position = lock(&self.position);
+        let bytes_written = self.lock().write_at(*position, buf)?;
+        *position += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.lock();
+        state.store()?;
+        state.blocks.file().flush()
+    }
+}
+
+impl<M, T> Seek for BlockFile<M, T>
+where
+    M: FileManager,
+    T: Format,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut position = lock(&self.position);
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.lock().len()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+        };
+        *position = new_position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(*position)
+    }
+}
+
+/// Transforms fixed-size blocks of a file's contents into the bytes that are
+/// stored in the underlying file, such as by encrypting them.
+pub trait BlockCodec: Debug + Send + Sync + 'static {
+    /// The number of bytes a stored block is larger than its contents.
+    fn overhead(&self) -> usize;
+
+    /// The number of bytes stored before the first block. By default, files
+    /// have no header.
+    fn header_size(&self) -> usize {
+        0
+    }
+
+    /// Returns the header to store at the start of a new file, which must
+    /// contain [`header_size()`](Self::header_size) bytes.
+    fn new_header(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Returns the stored form of `contents`, which is the block at `index`
+    /// of the file starting with `header`.
+    fn encode(&self, header: &[u8], index: u64, contents: &[u8]) -> Vec<u8>;
+
+    /// Returns the contents of the block at `index` of the file starting with
+    /// `header` from its stored form, or `None` if the stored block is
+    /// corrupt.
+    fn decode(&self, header: &[u8], index: u64, stored: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Contents stored as a sequence of independently encoded blocks.
+///
+/// Each block is stored at a fixed offset after the codec's header, so the
+/// length of the contents can be computed from the underlying file's length,
+/// and any block can be read or rewritten without touching the others. The
+/// header is written along with the first block.
+#[derive(Debug)]
+pub struct FixedBlocks<F, C> {
+    file: F,
+    codec: C,
+    block_size: usize,
+    /// The file's header, once it has been read or written.
+    header: Option<Vec<u8>>,
+}
+
+impl<F, C> FixedBlocks<F, C>
+where
+    F: File,
+    C: BlockCodec,
+{
+    /// Returns the contents stored in `file`, in blocks containing
+    /// `block_size` bytes that are encoded using `codec`.
+    pub fn new(file: F, codec: C, block_size: usize) -> Self {
+        Self {
+            file,
+            codec,
+            block_size,
+            header: None,
+        }
+    }
+
+    fn stored_block_size(&self) -> u64 {
+        (self.block_size + self.codec.overhead()) as u64
+    }
+
+    /// Returns the offset of the stored block at `index`.
+    fn block_offset(&self, index: u64) -> u64 {
+        self.codec.header_size() as u64 + index * self.stored_block_size()
+    }
+
+    /// Returns the number of bytes used to store the blocks.
+    fn stored_blocks_length(&mut self) -> io::Result<u64> {
+        let stored_length = self.file.len()?;
+        let header_size = self.codec.header_size() as u64;
+        if stored_length == 0 {
+            Ok(0)
+        } else if stored_length < header_size {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file header is truncated",
+            ))
+        } else {
+            Ok(stored_length - header_size)
+        }
+    }
+
+    /// Reads the file's header, or writes a new one if the file is empty.
+    fn load_header(&mut self) -> io::Result<()> {
+        if self.header.is_none() {
+            let header = if self.codec.header_size() == 0 {
+                Vec::new()
+            } else if self.file.len()? == 0 {
+                let header = self.codec.new_header();
+                self.file.seek(SeekFrom::Start(0))?;
+                self.file.write_all(&header)?;
+                header
+            } else {
+                let mut header = vec![0; self.codec.header_size()];
+                self.file.seek(SeekFrom::Start(0))?;
+                self.file.read_exact(&mut header)?;
+                header
+            };
+            self.header = Some(header);
+        }
+        Ok(())
+    }
+}
+
+impl<F, C> Blocks for FixedBlocks<F, C>
+where
+    F: File,
+    C: BlockCodec,
+{
+    type File = F;
+
+    fn file(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        let stored_length = self.stored_blocks_length()?;
+        let full_blocks = stored_length / self.stored_block_size();
+        let last_block = stored_length % self.stored_block_size();
+        let overhead = self.codec.overhead() as u64;
+        if last_block == 0 {
+            Ok(full_blocks * self.block_size as u64)
+        } else if last_block > overhead {
+            Ok(full_blocks * self.block_size as u64 + last_block - overhead)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "block at offset {} is truncated",
+                    full_blocks * self.block_size as u64
+                ),
+            ))
+        }
+    }
+
+    fn read_block(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let start = index * self.stored_block_size();
+        let stored_length = self.stored_blocks_length()?;
+        if start >= stored_length {
+            return Ok(Vec::new());
+        }
+
+        self.load_header()?;
+        let mut stored = vec![0; (stored_length - start).min(self.stored_block_size()) as usize];
+        self.file.seek(SeekFrom::Start(self.block_offset(index)))?;
+        self.file.read_exact(&mut stored)?;
+        let header = self.header.as_deref().unwrap_or_default();
+        self.codec.decode(header, index, &stored).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "block at offset {} is corrupt",
+                    index * self.block_size as u64
+                ),
+            )
+        })
+    }
+
+    fn write_block(&mut self, index: u64, contents: &[u8]) -> io::Result<()> {
+        self.load_header()?;
+        let header = self.header.as_deref().unwrap_or_default();
+        let stored = self.codec.encode(header, index, contents);
+        self.file.seek(SeekFrom::Start(self.block_offset(index)))?;
+        self.file.write_all(&stored)
+    }
+
+    fn set_len(&mut self, new_length: u64) -> io::Result<()> {
+        let block_size = self.block_size as u64;
+        let mut length = self.len()?;
+        if new_length < length {
+            let index = new_length / block_size;
+            let remaining = (new_length % block_size) as usize;
+            if remaining == 0 {
+                self.file.set_len(self.block_offset(index))
+            } else {
+                let mut block = self.read_block(index)?;
+                block.truncate(remaining);
+                self.write_block(index, &block)?;
+                self.file
+                    .set_len(self.block_offset(index) + (remaining + self.codec.overhead()) as u64)
+            }
+        } else {
+            while length < new_length {
+                let index = length / block_size;
+                let offset = length % block_size;
+                let mut block = if offset == 0 {
+                    Vec::new()
+                } else {
+                    self.read_block(index)?
+                };
+                let end = block_size.min(offset + (new_length - length));
+                block.resize(end as usize, 0);
+                self.write_block(index, &block)?;
+                length = index * block_size + end;
+            }
+            Ok(())
+        }
+    }
+}
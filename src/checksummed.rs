@@ -0,0 +1,91 @@
+#[cfg(doc)]
+use std::io;
+
+use crate::block::{BlockCodec, BlockFile, BlockFileManager, FixedBlocks, Format};
+use crate::crc32c::crc32c;
+use crate::{File, FileManager};
+
+/// The number of bytes of contents in each checksummed block, unless
+/// specified using [`ChecksummedFileManager::with_block_size()`].
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+/// A [`FileManager`] that verifies the integrity of another manager's files.
+///
+/// Each file's contents are split into fixed-size blocks, and every block is
+/// stored followed by a 4-byte CRC32C checksum of its position in the file and
+/// its contents. Blocks are verified whenever they are read from the
+/// underlying file, and reading a corrupt block fails with
+/// [`io::ErrorKind::InvalidData`] reporting the offset of the corrupt block.
+/// Writing to part of a block rewrites the entire block and its checksum. The
+/// most recently accessed block is kept in memory, and writes to it are only
+/// stored once the file is flushed, synced, resized or closed, or another
+/// block is accessed.
+///
+/// Checksums protect against accidental corruption, such as media errors or
+/// misdirected writes, but not against deliberate modification. Truncating a
+/// file at a block boundary isn't detected.
+pub type ChecksummedFileManager<M> = BlockFileManager<M, Checksums>;
+
+/// A file opened by a [`ChecksummedFileManager`].
+pub type ChecksummedFile<M> = BlockFile<M, Checksums>;
+
+impl<M> ChecksummedFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that checksums the files of `inner` in blocks of
+    /// [`DEFAULT_BLOCK_SIZE`].
+    pub fn new(inner: M) -> Self {
+        Self::with_block_size(inner, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Returns a manager that checksums the files of `inner` in blocks
+    /// containing `block_size` bytes. Files must always be opened with the
+    /// same block size they were written with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is zero.
+    pub fn with_block_size(inner: M, block_size: usize) -> Self {
+        assert!(block_size > 0, "block_size must be non-zero");
+        Self::with_format(inner, Checksums { block_size })
+    }
+}
+
+/// The format of the files stored by a [`ChecksummedFileManager`].
+#[derive(Clone, Debug)]
+pub struct Checksums {
+    block_size: usize,
+}
+
+impl Checksums {
+    fn compute(index: u64, contents: &[u8]) -> u32 {
+        !crc32c(crc32c(!0, &index.to_le_bytes()), contents)
+    }
+}
+
+impl Format for Checksums {
+    type Blocks<F: File> = FixedBlocks<F, Self>;
+
+    fn blocks<F: File>(&self, file: F) -> Self::Blocks<F> {
+        FixedBlocks::new(file, self.clone(), self.block_size)
+    }
+}
+
+impl BlockCodec for Checksums {
+    fn overhead(&self) -> usize {
+        4
+    }
+
+    fn encode(&self, _header: &[u8], index: u64, contents: &[u8]) -> Vec<u8> {
+        let mut stored = Vec::with_capacity(contents.len() + 4);
+        stored.extend_from_slice(contents);
+        stored.extend_from_slice(&Self::compute(index, contents).to_le_bytes());
+        stored
+    }
+
+    fn decode(&self, _header: &[u8], index: u64, stored: &[u8]) -> Option<Vec<u8>> {
+        let (contents, checksum) = stored.split_last_chunk::<4>()?;
+        (Self::compute(index, contents) == u32::from_le_bytes(*checksum)).then(|| contents.to_vec())
+    }
+}
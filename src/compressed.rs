@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+use std::io::{self, SeekFrom};
+
+use crate::block::{BlockFile, BlockFileManager, Blocks, Format};
+use crate::{File, FileManager, PathId};
+
+/// The number of bytes of contents in each compressed chunk, unless specified
+/// using [`CompressedFileManager::with_chunk_size()`].
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A chunk record: the tag, the chunk's index, its length, and the length of
+/// the compressed data that follows.
+const COMPRESSED_CHUNK: u8 = 0;
+/// A chunk record whose data is stored uncompressed, because compressing it
+/// didn't make it smaller.
+const RAW_CHUNK: u8 = 1;
+/// A record that sets the file's length: the tag, followed by the length.
+const LENGTH: u8 = 2;
+
+const CHUNK_HEADER_SIZE: u64 = 17;
+const LENGTH_RECORD_SIZE: u64 = 9;
+
+/// A [`FileManager`] that transparently compresses the contents of another
+/// manager's files.
+///
+/// Each file's contents are split into fixed-size chunks that are compressed
+/// independently using LZ4. Chunks are appended to the underlying file as
+/// records, and an index of where each chunk is stored is built by scanning
+/// the record headers when a file is opened. Reading at any offset only
+/// decompresses the chunk containing it, and writing rewrites only the chunks
+/// that are modified. Ranges of a file that have never been written, such as
+/// after extending it using [`File::set_len()`], take no space at all.
+///
+/// Overwriting a chunk appends a new version of it, leaving the old version in
+/// place until the file is rewritten using
+/// [`compact()`](Self::compact). A record that was only partially written,
+/// such as during a crash, is ignored and replaced by the next write.
+///
+/// The chunk that was accessed most recently is kept decompressed, and writes
+/// to it are only compressed and stored once another chunk is accessed, or the
+/// file is flushed, synced, resized or closed.
+///
+/// Compression doesn't detect corruption. To verify the stored data, wrap a
+/// [`ChecksummedFileManager`](crate::checksummed::ChecksummedFileManager).
+///
+/// This type is only available when the `compression` feature is enabled.
+pub type CompressedFileManager<M> = BlockFileManager<M, Compression>;
+
+/// A file opened by a [`CompressedFileManager`].
+pub type CompressedFile<M> = BlockFile<M, Compression>;
+
+impl<M> CompressedFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that compresses the files of `inner` in chunks of
+    /// [`DEFAULT_CHUNK_SIZE`].
+    pub fn new(inner: M) -> Self {
+        Self::with_chunk_size(inner, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Returns a manager that compresses the files of `inner` in chunks
+    /// containing `chunk_size` bytes. Larger chunks usually compress better,
+    /// but require more work to read or write a small range of a file. Files
+    /// must always be opened with the same chunk size they were written with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero or doesn't fit in a `u32`.
+    pub fn with_chunk_size(inner: M, chunk_size: usize) -> Self {
+        assert!(
+            chunk_size > 0 && u32::try_from(chunk_size).is_ok(),
+            "chunk_size must be non-zero and fit in a u32"
+        );
+        Self::with_format(inner, Compression { chunk_size })
+    }
+
+    /// Rewrites the file at `path` so that it only contains the current
+    /// version of each chunk, and returns the number of bytes reclaimed.
+    ///
+    /// The compacted file is written next to the original, with `.compacting`
+    /// appended to its name, and then renamed over it. Open handles to the
+    /// file keep working, and use the compacted file from then on. Fails with
+    /// [`io::ErrorKind::AlreadyExists`] if a file with the compacted file's
+    /// name already exists, such as one left behind by a crash while
+    /// compacting, which is never overwritten.
+    pub fn compact(&self, path: &PathId) -> io::Result<u64> {
+        self.compact_file(path, Chunks::compact_into)
+    }
+}
+
+/// The format of the files stored by a [`CompressedFileManager`].
+#[derive(Clone, Debug)]
+pub struct Compression {
+    chunk_size: usize,
+}
+
+impl Format for Compression {
+    type Blocks<F: File> = Chunks<F>;
+
+    fn blocks<F: File>(&self, file: F) -> Self::Blocks<F> {
+        Chunks::new(file, self.chunk_size)
+    }
+}
+
+fn length_record(length: u64) -> [u8; LENGTH_RECORD_SIZE as usize] {
+    let mut record = [LENGTH; LENGTH_RECORD_SIZE as usize];
+    record[1..].copy_from_slice(&length.to_le_bytes());
+    record
+}
+
+/// Where a chunk's latest version is stored.
+#[derive(Clone, Copy, Debug)]
+struct StoredChunk {
+    /// The offset of the chunk's data, after its record header.
+    offset: u64,
+    length: u32,
+    stored_length: u32,
+    compressed: bool,
+}
+
+impl StoredChunk {
+    /// Returns the length of the chunk's record, including its header.
+    fn record_length(&self) -> u64 {
+        CHUNK_HEADER_SIZE + u64::from(self.stored_length)
+    }
+}
+
+/// The index of an underlying file's chunks.
+#[derive(Debug)]
+pub struct Chunks<F> {
+    file: F,
+    chunk_size: usize,
+    index: BTreeMap<u64, StoredChunk>,
+    length: u64,
+    /// The end of the last complete record.
+    end: u64,
+    /// Whether the records have been scanned.
+    loaded: bool,
+    /// Whether the file ends with a partially written record, which must be
+    /// removed before appending.
+    torn: bool,
+}
+
+impl<F> Chunks<F>
+where
+    F: File,
+{
+    fn new(file: F, chunk_size: usize) -> Self {
+        Self {
+            file,
+            chunk_size,
+            index: BTreeMap::new(),
+            length: 0,
+            end: 0,
+            loaded: false,
+            torn: false,
+        }
+    }
+
+    /// Builds the index by scanning the record headers, the first time the
+    /// file is accessed.
+    fn load(&mut self) -> io::Result<()> {
+        if !self.loaded {
+            let stored_length = self.file.len()?;
+            self.scan(stored_length)?;
+            self.torn = stored_length > self.end;
+            self.loaded = true;
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, stored_length: u64) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.end))?;
+        while self.end < stored_length {
+            let mut tag = [0];
+            self.file.read_exact(&mut tag)?;
+            match tag[0] {
+                COMPRESSED_CHUNK | RAW_CHUNK => {
+                    if self.end + CHUNK_HEADER_SIZE > stored_length {
+                        break;
+                    }
+                    let mut header = [0; CHUNK_HEADER_SIZE as usize - 1];
+                    self.file.read_exact(&mut header)?;
+                    let index = u64::from_le_bytes(header[..8].try_into().expect("8 bytes"));
+                    let chunk = StoredChunk {
+                        offset: self.end + CHUNK_HEADER_SIZE,
+                        length: u32::from_le_bytes(header[8..12].try_into().expect("4 bytes")),
+                        stored_length: u32::from_le_bytes(
+                            header[12..].try_into().expect("4 bytes"),
+                        ),
+                        compressed: tag[0] == COMPRESSED_CHUNK,
+                    };
+                    let record_end = chunk.offset + u64::from(chunk.stored_length);
+                    if record_end > stored_length {
+                        break;
+                    }
+                    // Chunks are only stored compressed if that makes them
+                    // smaller, so neither length can exceed the chunk size.
+                    let chunk_end = index
+                        .checked_mul(self.chunk_size as u64)
+                        .and_then(|start| start.checked_add(u64::from(chunk.length)));
+                    if chunk_end.is_none()
+                        || chunk.length as usize > self.chunk_size
+                        || chunk.stored_length > chunk.length
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid chunk record at offset {}", self.end),
+                        ));
+                    }
+                    self.file.seek(SeekFrom::Start(record_end))?;
+                    self.end = record_end;
+                    self.apply_chunk(index, chunk);
+                }
+                LENGTH => {
+                    if self.end + LENGTH_RECORD_SIZE > stored_length {
+                        break;
+                    }
+                    let mut length = [0; 8];
+                    self.file.read_exact(&mut length)?;
+                    self.end += LENGTH_RECORD_SIZE;
+                    self.apply_length(u64::from_le_bytes(length));
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid record at offset {}", self.end),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_chunk(&mut self, index: u64, chunk: StoredChunk) {
+        self.length = self
+            .length
+            .max(index * self.chunk_size as u64 + u64::from(chunk.length));
+        self.index.insert(index, chunk);
+    }
+
+    fn apply_length(&mut self, length: u64) {
+        self.length = length;
+        self.index
+            .split_off(&length.div_ceil(self.chunk_size as u64));
+    }
+
+    /// Appends `records` after the last complete record, replacing any
+    /// partially written record.
+    fn append(&mut self, records: &[u8]) -> io::Result<()> {
+        if self.torn {
+            self.file.set_len(self.end)?;
+            self.torn = false;
+        }
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(records)?;
+        self.end += records.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the contents of the chunk at `index`, which is empty if the
+    /// chunk is beyond the end of the file.
+    fn read_chunk(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let start = index * self.chunk_size as u64;
+        let length = self
+            .length
+            .saturating_sub(start)
+            .min(self.chunk_size as u64);
+        let mut contents = vec![0; length as usize];
+        let Some(chunk) = self.index.get(&index).copied() else {
+            return Ok(contents);
+        };
+
+        let mut stored = vec![0; chunk.stored_length as usize];
+        self.file.seek(SeekFrom::Start(chunk.offset))?;
+        self.file.read_exact(&mut stored)?;
+        let decompressed = if chunk.compressed {
+            lz4_flex::block::decompress(&stored, chunk.length as usize)
+                .ok()
+                .filter(|decompressed| decompressed.len() == chunk.length as usize)
+        } else {
+            Some(stored)
+        };
+        let decompressed = decompressed.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk at offset {start} is corrupt"),
+            )
+        })?;
+
+        let copied = contents.len().min(decompressed.len());
+        contents[..copied].copy_from_slice(&decompressed[..copied]);
+        Ok(contents)
+    }
+
+    /// Returns the record storing `contents` as the chunk at `index`.
+    fn chunk_record(index: u64, contents: &[u8]) -> Vec<u8> {
+        let compressed = lz4_flex::block::compress(contents);
+        let (tag, data) = if compressed.len() < contents.len() {
+            (COMPRESSED_CHUNK, compressed.as_slice())
+        } else {
+            (RAW_CHUNK, contents)
+        };
+        let mut record = Vec::with_capacity(CHUNK_HEADER_SIZE as usize + data.len());
+        record.push(tag);
+        record.extend_from_slice(&index.to_le_bytes());
+        record.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// Returns where the chunk stored by `record` is located, if the record
+    /// is written at `offset`.
+    fn stored_chunk(offset: u64, record: &[u8]) -> StoredChunk {
+        StoredChunk {
+            offset: offset + CHUNK_HEADER_SIZE,
+            length: u32::from_le_bytes(record[9..13].try_into().expect("4 bytes")),
+            stored_length: (record.len() as u64 - CHUNK_HEADER_SIZE) as u32,
+            compressed: record[0] == COMPRESSED_CHUNK,
+        }
+    }
+
+    fn write_chunk(&mut self, index: u64, contents: &[u8]) -> io::Result<()> {
+        let record = Self::chunk_record(index, contents);
+        let offset = self.end;
+        self.append(&record)?;
+        self.apply_chunk(index, Self::stored_chunk(offset, &record));
+        Ok(())
+    }
+
+    fn resize(&mut self, new_length: u64) -> io::Result<()> {
+        if new_length == self.length {
+            return Ok(());
+        }
+
+        let chunk_size = self.chunk_size as u64;
+        let last = new_length / chunk_size;
+        let remaining = new_length % chunk_size;
+        let mut records = Vec::new();
+        if new_length < self.length
+            && remaining > 0
+            && self
+                .index
+                .get(&last)
+                .is_some_and(|chunk| u64::from(chunk.length) > remaining)
+        {
+            // The bytes being removed from the last chunk must not reappear
+            // if the file is extended again.
+            let mut contents = self.read_chunk(last)?;
+            contents.truncate(remaining as usize);
+            records = Self::chunk_record(last, &contents);
+        }
+        let truncated_chunk = records.len();
+        records.extend_from_slice(&length_record(new_length));
+
+        let offset = self.end;
+        self.append(&records)?;
+        if truncated_chunk > 0 {
+            self.apply_chunk(
+                last,
+                Self::stored_chunk(offset, &records[..truncated_chunk]),
+            );
+        }
+        self.apply_length(new_length);
+        Ok(())
+    }
+
+    /// Writes the latest version of each chunk to the empty file
+    /// `destination`, followed by the length if it isn't implied by the last
+    /// chunk.
+    fn compact_into(&mut self, destination: &mut F) -> io::Result<()> {
+        self.load()?;
+        let mut implied_length = 0;
+        let chunk_size = self.chunk_size as u64;
+        for (&index, chunk) in &self.index {
+            let mut record = vec![0; chunk.record_length() as usize];
+            self.file
+                .seek(SeekFrom::Start(chunk.offset - CHUNK_HEADER_SIZE))?;
+            self.file.read_exact(&mut record)?;
+            destination.write_all(&record)?;
+            implied_length = index * chunk_size + u64::from(chunk.length);
+        }
+        if self.length != implied_length {
+            destination.write_all(&length_record(self.length))?;
+        }
+        Ok(())
+    }
+}
+
+impl<F> Blocks for Chunks<F>
+where
+    F: File,
+{
+    type File = F;
+
+    fn file(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    fn block_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        self.load()?;
+        Ok(self.length)
+    }
+
+    fn read_block(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        self.load()?;
+        self.read_chunk(index)
+    }
+
+    fn write_block(&mut self, index: u64, contents: &[u8]) -> io::Result<()> {
+        self.load()?;
+        self.write_chunk(index, contents)
+    }
+
+    fn set_len(&mut self, new_length: u64) -> io::Result<()> {
+        self.load()?;
+        self.resize(new_length)
+    }
+}
@@ -0,0 +1,33 @@
+/// The lookup table for CRC32C (Castagnoli), using the reflected polynomial.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// Continues a CRC32C computation from `crc` over `bytes`. The result must be
+/// inverted once all bytes have been processed.
+pub(crate) fn crc32c(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        CRC32C_TABLE[usize::from(crc as u8 ^ byte)] ^ (crc >> 8)
+    })
+}
+
+#[test]
+fn crc32c_check_value() {
+    assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+}
@@ -0,0 +1,153 @@
+use std::fmt::Debug;
+#[cfg(doc)]
+use std::io;
+use std::sync::Arc;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::block::{BlockCodec, BlockFile, BlockFileManager, FixedBlocks, Format};
+use crate::{File, FileManager};
+
+/// The number of bytes of contents in each encrypted block, unless specified
+/// using [`EncryptedFileManager::with_block_size()`].
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// The size of the random identifier stored at the start of each file.
+const FILE_ID_SIZE: usize = 16;
+
+/// A [`FileManager`] that encrypts the contents of another manager's files.
+///
+/// Each file's contents are split into fixed-size blocks that are encrypted
+/// independently using XChaCha20-Poly1305, with a random nonce for every write
+/// of a block. Files can still be read, written and resized at any offset, and
+/// only the affected blocks are decrypted and reencrypted. Each stored block
+/// is 40 bytes larger than its contents, and each file starts with a random
+/// 16-byte identifier. The most recently accessed block is kept decrypted in
+/// memory, and writes to it are only encrypted and stored once the file is
+/// flushed, synced, resized or closed, or another block is accessed.
+///
+/// Every block is authenticated along with its file's identifier and its
+/// position in the file, so modifying, corrupting or moving a block, including
+/// into another file encrypted with the same key, causes reading it to fail
+/// with [`io::ErrorKind::InvalidData`]. Replacing an entire file with another
+/// file encrypted with the same key, truncating a file at a block boundary,
+/// or replacing a block with an older version of the same block, isn't
+/// detected. File names and approximate file lengths aren't hidden.
+///
+/// This type is only available when the `encryption` feature is enabled.
+pub type EncryptedFileManager<M> = BlockFileManager<M, Encryption>;
+
+/// A file opened by an [`EncryptedFileManager`].
+pub type EncryptedFile<M> = BlockFile<M, Encryption>;
+
+impl<M> EncryptedFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that encrypts the files of `inner` using `key`, with
+    /// blocks of [`DEFAULT_BLOCK_SIZE`].
+    pub fn new(inner: M, key: &[u8; 32]) -> Self {
+        Self::with_block_size(inner, key, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Returns a manager that encrypts the files of `inner` using `key`, with
+    /// blocks containing `block_size` bytes. Files must always be opened with
+    /// the same key and block size they were written with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is zero.
+    pub fn with_block_size(inner: M, key: &[u8; 32], block_size: usize) -> Self {
+        assert!(block_size > 0, "block_size must be non-zero");
+        Self::with_format(
+            inner,
+            Encryption {
+                cipher: Arc::new(XChaCha20Poly1305::new(key.into())),
+                block_size,
+            },
+        )
+    }
+}
+
+/// The format of the files stored by an [`EncryptedFileManager`].
+#[derive(Clone)]
+pub struct Encryption {
+    cipher: Arc<XChaCha20Poly1305>,
+    block_size: usize,
+}
+
+impl Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encryption")
+            .field("block_size", &self.block_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Format for Encryption {
+    type Blocks<F: File> = FixedBlocks<F, Self>;
+
+    fn blocks<F: File>(&self, file: F) -> Self::Blocks<F> {
+        FixedBlocks::new(file, self.clone(), self.block_size)
+    }
+}
+
+/// Returns the associated data that authenticates the block at `index` of the
+/// file identified by `header`.
+fn associated_data(header: &[u8], index: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + 8);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad
+}
+
+impl BlockCodec for Encryption {
+    fn overhead(&self) -> usize {
+        NONCE_SIZE + TAG_SIZE
+    }
+
+    fn header_size(&self) -> usize {
+        FILE_ID_SIZE
+    }
+
+    fn new_header(&self) -> Vec<u8> {
+        let mut file_id = vec![0; FILE_ID_SIZE];
+        OsRng.fill_bytes(&mut file_id);
+        file_id
+    }
+
+    fn encode(&self, header: &[u8], index: u64, contents: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: contents,
+                    aad: &associated_data(header, index),
+                },
+            )
+            .expect("blocks are small enough to encrypt");
+        let mut stored = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+        stored
+    }
+
+    fn decode(&self, header: &[u8], index: u64, stored: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, ciphertext) = stored.split_at_checked(NONCE_SIZE)?;
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(header, index),
+                },
+            )
+            .ok()
+    }
+}
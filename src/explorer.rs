@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::memory::{MemoryFile, MemoryFileManager};
+use crate::tree::sync_tree;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// Systematically tests that a workload can recover from a crash at any point.
+///
+/// A workload is executed once against a [`MemoryFileManager`] while every
+/// mutating operation is recorded. Afterwards, the file system is rebuilt as
+/// it could exist after a crash following each prefix of the recorded
+/// operations, and a checker is invoked on each of the rebuilt file systems.
+///
+/// For each crash point, the operations that are covered by a later sync are
+/// always persisted. Every other operation may or may not have been persisted
+/// when the crash occurred, so each combination of them is explored
+/// separately. An operation is covered by a sync when:
+///
+/// - writes and length changes: the file is synced using
+///   [`File::sync_data()`] or [`File::sync_all()`].
+/// - creating, renaming or removing files and directories: the parent
+///   directory is synced. Renames require both parent directories to be
+///   synced.
+///
+/// The unsynced writes and length changes of a file may also have been
+/// persisted in a different order than they were performed, so every order
+/// that produces different contents is explored as well, up to the limit set
+/// using [`CrashExplorer::exhaustive_limit()`]. All other operations are
+/// persisted in the order they were performed.
+#[derive(Debug, Clone)]
+pub struct CrashExplorer {
+    exhaustive_limit: usize,
+}
+
+impl Default for CrashExplorer {
+    fn default() -> Self {
+        Self {
+            exhaustive_limit: 10,
+        }
+    }
+}
+
+impl CrashExplorer {
+    /// Returns a new explorer with the default settings.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of unsynced operations for which every
+    /// combination and order is explored. Defaults to 10.
+    ///
+    /// When a crash point has more unsynced operations than this limit, only
+    /// persisting none of them, each one individually, and all of them in the
+    /// order they were performed are explored.
+    ///
+    /// The orders are limited as well: at most `2^limit` combinations and
+    /// orders are explored for each crash point. When the orders of every
+    /// combination would exceed that, each combination is explored in the
+    /// order it was performed, and as many as fit within the limit are also
+    /// explored with each file's writes and length changes persisted in
+    /// reverse.
+    #[must_use]
+    pub const fn exhaustive_limit(mut self, limit: usize) -> Self {
+        self.exhaustive_limit = limit;
+        self
+    }
+
+    /// Executes `workload` and then invokes `checker` for every crash state
+    /// that can be produced by the workload's operations.
+    ///
+    /// `checker` is given a manager containing only the persisted state, as it
+    /// would exist after restarting. It should perform the workload's recovery
+    /// and verify the result. The first error returned by `checker` stops the
+    /// exploration.
+    ///
+    /// Returns the number of crash states checked.
+    pub fn explore<Workload, Checker, E>(
+        &self,
+        workload: Workload,
+        mut checker: Checker,
+    ) -> Result<usize, Inconsistency<E>>
+    where
+        Workload: FnOnce(&MemoryFileManager),
+        Checker: FnMut(&MemoryFileManager, &CrashPoint) -> Result<(), E>,
+    {
+        let manager = MemoryFileManager::default();
+        manager.start_recording();
+        workload(&manager);
+        let operations = manager.finish_recording();
+        // The workload's manager is no longer needed. Errors are ignored,
+        // because they only indicate the fsync threads weren't running.
+        let _result = manager.shutdown();
+
+        let mut states_checked = 0;
+        for completed in 0..=operations.len() {
+            let prefix = &operations[..completed];
+            let synced = synced_operations(prefix);
+            let unsynced = prefix
+                .iter()
+                .enumerate()
+                .filter(|(index, operation)| !synced[*index] && !operation.is_sync())
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+
+            for persisted_unsynced in self.combinations(prefix, &unsynced) {
+                let crashed = replay(prefix, &synced, &persisted_unsynced);
+                let crash_point = CrashPoint {
+                    completed,
+                    persisted_unsynced,
+                };
+                checker(&crashed, &crash_point).map_err(|error| Inconsistency {
+                    operations: operations.clone(),
+                    crash_point,
+                    error,
+                })?;
+                states_checked += 1;
+            }
+        }
+
+        Ok(states_checked)
+    }
+
+    fn combinations(&self, operations: &[Operation], unsynced: &[usize]) -> Vec<Vec<usize>> {
+        if unsynced.len() <= self.exhaustive_limit && unsynced.len() < usize::BITS as usize {
+            let subsets = (0_usize..1 << unsynced.len())
+                .map(|mask| {
+                    unsynced
+                        .iter()
+                        .enumerate()
+                        .filter(|(bit, _)| mask & (1 << bit) != 0)
+                        .map(|(_, index)| *index)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            let limit = u32::try_from(self.exhaustive_limit)
+                .ok()
+                .and_then(|limit| 1_usize.checked_shl(limit))
+                .unwrap_or(usize::MAX);
+            let mut orders = Vec::new();
+            for persisted in &subsets {
+                match reorderings(operations, persisted, limit - orders.len()) {
+                    Some(reorderings) => orders.extend(reorderings),
+                    None => {
+                        let reversed = subsets
+                            .iter()
+                            .filter_map(|persisted| reversed(operations, persisted))
+                            .take(limit - subsets.len())
+                            .collect::<Vec<_>>();
+                        return subsets.into_iter().chain(reversed).collect();
+                    }
+                }
+            }
+            orders
+        } else {
+            let mut combinations = vec![Vec::new()];
+            combinations.extend(unsynced.iter().map(|index| vec![*index]));
+            combinations.push(unsynced.to_vec());
+            combinations
+        }
+    }
+}
+
+/// Returns whether each operation is covered by a later sync.
+fn synced_operations(operations: &[Operation]) -> Vec<bool> {
+    let mut synced_files = HashSet::new();
+    let mut synced_directories = HashSet::new();
+    // Removing / is covered by syncing / itself.
+    let parent_synced = |synced_directories: &HashSet<PathId>, path: &PathId| {
+        synced_directories.contains(&path.parent().unwrap_or_else(|| path.clone()))
+    };
+
+    let mut synced = vec![false; operations.len()];
+    for (index, operation) in operations.iter().enumerate().rev() {
+        synced[index] = match operation {
+            Operation::SyncFile { file } => {
+                synced_files.insert(*file);
+                true
+            }
+            Operation::SyncDirectory { path } => {
+                synced_directories.insert(path.clone());
+                true
+            }
+            Operation::Write { file, .. } | Operation::SetLength { file, .. } => {
+                synced_files.contains(file)
+            }
+            Operation::CreateFile { path, .. }
+            | Operation::CreateDirectory { path }
+            | Operation::RemoveFile { path }
+            | Operation::RemoveDirectory { path } => parent_synced(&synced_directories, path),
+            Operation::Rename { from, to } => {
+                parent_synced(&synced_directories, from) && parent_synced(&synced_directories, to)
+            }
+        };
+    }
+    synced
+}
+
+/// Returns the positions within `persisted` of each file's writes and length
+/// changes, for the files with more than one of them. A BTreeMap keeps the
+/// exploration order reproducible.
+fn reorderable(operations: &[Operation], persisted: &[usize]) -> BTreeMap<u64, Vec<usize>> {
+    let mut files = BTreeMap::<u64, Vec<usize>>::new();
+    for (position, index) in persisted.iter().enumerate() {
+        if let Operation::Write { file, .. } | Operation::SetLength { file, .. } =
+            &operations[*index]
+        {
+            files.entry(*file).or_default().push(position);
+        }
+    }
+    files.retain(|_, positions| positions.len() > 1);
+    files
+}
+
+/// Returns every order in which the `persisted` operations could have been
+/// persisted, as the operation persisted in place of each of them, or `None`
+/// if there are more than `limit` orders.
+///
+/// Only the writes and length changes of each file are reordered. Orders that
+/// only differ by swapping writes that don't overlap are skipped, as they
+/// produce the same contents.
+fn reorderings(
+    operations: &[Operation],
+    persisted: &[usize],
+    limit: usize,
+) -> Option<Vec<Vec<usize>>> {
+    let mut orders = vec![persisted.to_vec()];
+    for positions in reorderable(operations, persisted).values() {
+        let mut remaining = positions
+            .iter()
+            .map(|position| persisted[*position])
+            .collect::<Vec<_>>();
+        let mut permutations = Vec::new();
+        permute(
+            operations,
+            &mut remaining,
+            &mut Vec::new(),
+            &mut permutations,
+            limit / orders.len(),
+        );
+        if orders.len() * permutations.len() > limit {
+            return None;
+        }
+        orders = orders
+            .iter()
+            .flat_map(|order| {
+                permutations.iter().map(move |permutation| {
+                    let mut order = order.clone();
+                    for (position, index) in positions.iter().zip(permutation) {
+                        order[*position] = *index;
+                    }
+                    order
+                })
+            })
+            .collect();
+    }
+    if orders.len() > limit {
+        return None;
+    }
+    Some(orders)
+}
+
+/// Returns the order of the `persisted` operations in which each file's
+/// writes and length changes are reversed, or `None` if no file has more than
+/// one of them.
+fn reversed(operations: &[Operation], persisted: &[usize]) -> Option<Vec<usize>> {
+    let files = reorderable(operations, persisted);
+    if files.is_empty() {
+        return None;
+    }
+
+    let mut reversed = persisted.to_vec();
+    for positions in files.values() {
+        for (position, original) in positions.iter().zip(positions.iter().rev()) {
+            reversed[*position] = persisted[*original];
+        }
+    }
+    Some(reversed)
+}
+
+/// Pushes every order of `remaining` onto `permutations`, skipping orders in
+/// which two adjacent operations that don't overlap are out of order. Stops
+/// once more than `limit` orders have been found.
+fn permute(
+    operations: &[Operation],
+    remaining: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+    permutations: &mut Vec<Vec<usize>>,
+    limit: usize,
+) {
+    if permutations.len() > limit {
+        return;
+    }
+    if remaining.is_empty() {
+        permutations.push(order.clone());
+        return;
+    }
+
+    for position in 0..remaining.len() {
+        let next = remaining[position];
+        if let Some(&previous) = order.last() {
+            if previous > next && !operations[previous].overlaps(&operations[next]) {
+                continue;
+            }
+        }
+
+        remaining.remove(position);
+        order.push(next);
+        permute(operations, remaining, order, permutations, limit);
+        order.pop();
+        remaining.insert(position, next);
+    }
+}
+
+/// Builds a new file system by applying the persisted operations in order.
+/// The unsynced operations are applied in the order of `persisted_unsynced`,
+/// which only differs from the order they were performed when writes were
+/// reordered.
+fn replay(
+    operations: &[Operation],
+    synced: &[bool],
+    persisted_unsynced: &[usize],
+) -> MemoryFileManager {
+    let manager = MemoryFileManager::default();
+    let mut files = HashMap::new();
+    let mut reordered = persisted_unsynced.iter();
+    for (index, synced) in synced.iter().enumerate() {
+        let index = if *synced {
+            index
+        } else if persisted_unsynced.contains(&index) {
+            *reordered.next().expect("one operation for each position")
+        } else {
+            continue;
+        };
+        // Operations fail when they depend on an operation that wasn't
+        // persisted, such as writing to a file whose creation was lost.
+        // These operations can't have been persisted either.
+        let _result = operations[index].apply(&manager, &mut files);
+    }
+
+    // Everything that survived the crash is durable.
+    sync_tree(&manager, &PathId::root())
+        .expect("memory file manager operations are infallible after replaying");
+    manager
+}
+
+/// A mutating operation recorded by a [`CrashExplorer`].
+///
+/// Files are identified by a unique id instead of a path, as a file handle
+/// remains valid across renames.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Operation {
+    /// A new file was created.
+    CreateFile {
+        /// The path of the new file.
+        path: PathId,
+        /// The id of the new file.
+        file: u64,
+    },
+    /// A new directory was created.
+    CreateDirectory {
+        /// The path of the new directory.
+        path: PathId,
+    },
+    /// Bytes were written to a file.
+    Write {
+        /// The id of the file written to.
+        file: u64,
+        /// The offset of the first byte written.
+        offset: u64,
+        /// The bytes written.
+        bytes: Vec<u8>,
+    },
+    /// A file's length was changed.
+    SetLength {
+        /// The id of the file.
+        file: u64,
+        /// The new length of the file.
+        length: u64,
+    },
+    /// A file's contents were synced.
+    SyncFile {
+        /// The id of the file.
+        file: u64,
+    },
+    /// A directory's entries were synced.
+    SyncDirectory {
+        /// The path of the directory.
+        path: PathId,
+    },
+    /// A file or directory was renamed.
+    Rename {
+        /// The original path.
+        from: PathId,
+        /// The new path.
+        to: PathId,
+    },
+    /// A file was removed.
+    RemoveFile {
+        /// The path of the removed file.
+        path: PathId,
+    },
+    /// A directory and all of its contents were removed.
+    RemoveDirectory {
+        /// The path of the removed directory.
+        path: PathId,
+    },
+}
+
+impl Operation {
+    fn is_sync(&self) -> bool {
+        matches!(
+            self,
+            Operation::SyncFile { .. } | Operation::SyncDirectory { .. }
+        )
+    }
+
+    /// Returns whether the order of this operation and `other`, which affect
+    /// the same file, changes the file's contents.
+    fn overlaps(&self, other: &Operation) -> bool {
+        match (self, other) {
+            (
+                Operation::Write { offset, bytes, .. },
+                Operation::Write {
+                    offset: other_offset,
+                    bytes: other_bytes,
+                    ..
+                },
+            ) => {
+                *offset < other_offset + other_bytes.len() as u64
+                    && *other_offset < offset + bytes.len() as u64
+            }
+            _ => true,
+        }
+    }
+
+    fn apply(
+        &self,
+        manager: &MemoryFileManager,
+        files: &mut HashMap<u64, MemoryFile>,
+    ) -> io::Result<()> {
+        match self {
+            Operation::CreateFile { path, file } => {
+                let handle = manager.open(path, OpenOptions::new().write(true).create(true))?;
+                files.insert(*file, handle);
+                Ok(())
+            }
+            Operation::CreateDirectory { path } => {
+                // Only the directory itself is created by this operation. Its
+                // parent must have already been created.
+                if path.parent().is_none_or(|parent| manager.exists(&parent)) {
+                    manager.create_dir_all(path)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::NotFound))
+                }
+            }
+            Operation::Write {
+                file,
+                offset,
+                bytes,
+            } => {
+                let file = files
+                    .get_mut(file)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                file.seek(SeekFrom::Start(*offset))?;
+                file.write_all(bytes)
+            }
+            Operation::SetLength { file, length } => files
+                .get(file)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?
+                .set_len(*length),
+            Operation::Rename { from, to } => manager.rename(from, to.clone()),
+            Operation::RemoveFile { path } => manager.remove_file(path),
+            Operation::RemoveDirectory { path } => manager.remove_dir_all(path),
+            Operation::SyncFile { .. } | Operation::SyncDirectory { .. } => Ok(()),
+        }
+    }
+}
+
+/// A point at which a [`CrashExplorer`] simulated a crash.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CrashPoint {
+    /// The number of recorded operations that completed before the crash.
+    pub completed: usize,
+    /// The indices of the completed operations that weren't covered by a sync,
+    /// but were persisted anyways.
+    ///
+    /// When writes were persisted in a different order than they were
+    /// performed, the indices are listed in the order they were persisted.
+    pub persisted_unsynced: Vec<usize>,
+}
+
+impl Display for CrashPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "crash after {} operations, persisting unsynced operations {:?}",
+            self.completed, self.persisted_unsynced
+        )
+    }
+}
+
+/// An error returned by the checker of a [`CrashExplorer`].
+#[derive(Debug)]
+pub struct Inconsistency<E> {
+    /// Every operation the workload performed.
+    pub operations: Vec<Operation>,
+    /// The crash that produced the state the checker rejected.
+    pub crash_point: CrashPoint,
+    /// The error returned by the checker.
+    pub error: E,
+}
+
+impl<E> Error for Inconsistency<E> where E: Error {}
+
+impl<E> Display for Inconsistency<E>
+where
+    E: Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.crash_point, self.error)
+    }
+}
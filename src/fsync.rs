@@ -69,7 +69,10 @@ where
 
     pub fn new_batch(&self) -> Result<FSyncBatch<M>, FSyncError> {
         let notify = Arc::new(FSyncNotify {
-            remaining: Mutex::new(0),
+            state: Mutex::new(NotifyState {
+                remaining: 0,
+                error: None,
+            }),
             sync: Condvar::new(),
         });
 
@@ -130,15 +133,19 @@ where
             spawn_status = SpawnStatus::Spawned(handle);
         }
 
-        if fsync.all {
-            fsync.file.sync_all()?;
+        let result = if fsync.all {
+            fsync.file.sync_all()
         } else {
-            fsync.file.sync_data()?;
+            fsync.file.sync_data()
+        };
+
+        let mut state = fsync.notify.state.lock()?;
+        state.remaining -= 1;
+        if let Err(err) = result {
+            // Only the first error is reported by the batch.
+            state.error.get_or_insert(err);
         }
-
-        let mut remaining_syncs = fsync.notify.remaining.lock()?;
-        *remaining_syncs -= 1;
-        drop(remaining_syncs);
+        drop(state);
         fsync.notify.sync.notify_one();
     }
 
@@ -183,9 +190,9 @@ where
     M: FileManager,
 {
     pub fn queue_fsync_all(&self, file: M::File) -> Result<(), FSyncError> {
-        let mut remaining_syncs = self.notify.remaining.lock()?;
-        *remaining_syncs += 1;
-        drop(remaining_syncs);
+        let mut state = self.notify.state.lock()?;
+        state.remaining += 1;
+        drop(state);
 
         self.command_sender
             .send(FSync {
@@ -199,9 +206,9 @@ where
     }
 
     pub fn queue_fsync_data(&self, file: M::File) -> Result<(), FSyncError> {
-        let mut remaining_syncs = self.notify.remaining.lock()?;
-        *remaining_syncs += 1;
-        drop(remaining_syncs);
+        let mut state = self.notify.state.lock()?;
+        state.remaining += 1;
+        drop(state);
 
         self.command_sender
             .send(FSync {
@@ -214,23 +221,34 @@ where
         Ok(())
     }
 
+    /// Waits for all queued syncs to complete. If any sync failed, the first
+    /// error encountered is returned.
     pub fn wait_all(self) -> Result<(), FSyncError> {
-        let mut remaining_syncs = self.notify.remaining.lock()?;
+        let mut state = self.notify.state.lock()?;
 
-        while *remaining_syncs > 0 {
-            remaining_syncs = self.notify.sync.wait(remaining_syncs)?;
+        while state.remaining > 0 {
+            state = self.notify.sync.wait(state)?;
         }
 
-        Ok(())
+        match state.error.take() {
+            Some(err) => Err(FSyncError::Io(err)),
+            None => Ok(()),
+        }
     }
 }
 
 #[derive(Debug)]
 struct FSyncNotify {
-    remaining: Mutex<usize>,
+    state: Mutex<NotifyState>,
     sync: Condvar,
 }
 
+#[derive(Debug)]
+struct NotifyState {
+    remaining: usize,
+    error: Option<io::Error>,
+}
+
 #[derive(Debug)]
 pub enum FSyncError {
     Shutdown,
@@ -268,7 +286,7 @@ impl From<FSyncError> for io::Error {
     fn from(error: FSyncError) -> Self {
         match error {
             FSyncError::Io(io) => io,
-            other => Self::new(io::ErrorKind::Other, other),
+            other => Self::other(other),
         }
     }
 }
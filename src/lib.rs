@@ -1,7 +1,23 @@
+pub mod block;
+pub mod checksummed;
+#[cfg(feature = "compression")]
+pub mod compressed;
+mod crc32c;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+pub mod explorer;
 pub mod fs;
 mod fsync;
 pub mod memory;
+pub mod metrics;
+pub mod overlay;
+pub mod read_only;
+mod rng;
+pub mod scoped;
+pub mod trace;
+mod tree;
 pub use fsync::{FSyncBatch, FSyncError};
+pub use tree::{copy_tree, copy_tree_incremental, diff_trees, ModifiedFile, TreeDiff};
 
 use std::borrow::Cow;
 use std::fmt::Debug;
@@ -100,6 +116,12 @@ pub struct OpenOptions {
     pub create: bool,
 }
 
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OpenOptions {
     pub const fn new() -> Self {
         Self {
@@ -1,36 +1,550 @@
+#[cfg(feature = "archive")]
+mod archive;
+mod faults;
+
+pub use self::faults::{
+    BitRot, Fault, FaultSchedule, Latency, LatencyProfile, OperationKind, PartialIo,
+};
+
 use std::borrow::Cow;
-use std::cmp::Ordering;
 use std::collections::{hash_map, HashMap, HashSet};
+use std::ffi::OsString;
 use std::io::{self, Read, Seek, Write};
 use std::num::TryFromIntError;
+use std::ops::Range;
 use std::path::{PathBuf, MAIN_SEPARATOR};
-use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
+use crate::explorer::Operation;
 use crate::fsync::FSyncManager;
-use crate::{File, FileManager, OpenOptions, PathId};
+use crate::rng::Rng;
+use crate::tree::sync_tree;
+use crate::{copy_tree, File, FileManager, OpenOptions, PathId};
 
 #[derive(Clone, Debug)]
 pub struct MemoryFileManager {
     /// The directory structure. If both directories and files need to be
     /// locked, lock directories first.
-    directories: Arc<Mutex<HashMap<PathId, HashSet<PathId>>>>,
+    directories: Arc<Directories>,
     /// Every file and directory known to this virtual file system.
-    files: Arc<RwLock<HashMap<PathId, MemoryFile>>>,
+    files: Arc<Files>,
+    /// State shared with all files of this file system.
+    volume: Arc<Volume>,
+    /// If set, unsynced writes may be partially persisted when crashing.
+    torn_writes: Arc<Mutex<Option<TornWriter>>>,
+    /// Named states that can be restored. Lock this before directories and
+    /// files if both need to be locked.
+    checkpoints: Arc<Mutex<HashMap<String, Checkpoint>>>,
     fsyncs: FSyncManager<Self>,
 }
 
+impl MemoryFileManager {
+    /// Returns a new manager whose files can't grow beyond `maximum_bytes` in
+    /// total. Writes that would exceed the limit fail with
+    /// [`io::ErrorKind::StorageFull`].
+    #[must_use]
+    pub fn with_maximum_size(maximum_bytes: u64) -> Self {
+        let manager = Self::default();
+        manager.set_maximum_size(Some(maximum_bytes));
+        manager
+    }
+
+    /// Sets the maximum number of bytes all files may occupy in total. `None`
+    /// removes the limit.
+    ///
+    /// Lowering the limit below [`used_size()`](Self::used_size) doesn't
+    /// remove any data, but prevents all files from growing until enough space
+    /// has been freed.
+    pub fn set_maximum_size(&self, maximum_bytes: Option<u64>) {
+        self.volume
+            .storage
+            .maximum
+            .store(maximum_bytes.unwrap_or(u64::MAX), atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of bytes all files may occupy in total, if
+    /// limited.
+    #[must_use]
+    pub fn maximum_size(&self) -> Option<u64> {
+        match self.volume.storage.maximum.load(atomic::Ordering::Relaxed) {
+            u64::MAX => None,
+            maximum => Some(maximum),
+        }
+    }
+
+    /// Returns the number of bytes currently occupied by file contents.
+    ///
+    /// Removing a file only frees its space once every open handle to it has
+    /// been dropped.
+    #[must_use]
+    pub fn used_size(&self) -> u64 {
+        self.volume.storage.used.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Marks `region` of the file at `path` as bad. Any read or write that
+    /// touches the region, as selected by `access`, fails with an error of
+    /// `kind`.
+    ///
+    /// Bad regions belong to the file's contents: they follow the file when
+    /// it is renamed and are discarded when it is removed.
+    pub fn add_bad_region(
+        &self,
+        path: &PathId,
+        region: Range<u64>,
+        access: RegionAccess,
+        kind: io::ErrorKind,
+    ) -> io::Result<()> {
+        let buffer = self.buffer(path)?;
+        let mut buffer = buffer.write().map_err(PoisonError::to_io)?;
+        buffer.bad_regions.push(BadRegion {
+            region,
+            access,
+            kind,
+        });
+        Ok(())
+    }
+
+    /// Removes all bad regions from the file at `path`.
+    pub fn clear_bad_regions(&self, path: &PathId) -> io::Result<()> {
+        let buffer = self.buffer(path)?;
+        let mut buffer = buffer.write().map_err(PoisonError::to_io)?;
+        buffer.bad_regions.clear();
+        Ok(())
+    }
+
+    /// Flips the bits set in `mask` of the byte at `offset` in the file at
+    /// `path`, without going through [`Write`].
+    ///
+    /// This simulates silent corruption by the storage media: both the
+    /// file's current contents and its durable contents are corrupted, and
+    /// open files observe the change immediately.
+    pub fn flip_bits(&self, path: &PathId, offset: u64, mask: u8) -> io::Result<()> {
+        self.corrupt(path, offset, 1, |_, byte| byte ^ mask)
+    }
+
+    /// Overwrites the bytes starting at `offset` in the file at `path` with
+    /// `bytes`, without going through [`Write`]. The overwritten bytes must be
+    /// within the file's current length.
+    ///
+    /// Like [`flip_bits()`](Self::flip_bits), this corrupts both the file's
+    /// current and durable contents.
+    pub fn corrupt_bytes(&self, path: &PathId, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        self.corrupt(path, offset, bytes.len(), |index, _| bytes[index])
+    }
+
+    fn corrupt(
+        &self,
+        path: &PathId,
+        offset: u64,
+        length: usize,
+        corrupt: impl FnMut(usize, u8) -> u8,
+    ) -> io::Result<()> {
+        let offset = usize::try_from(offset).map_err(ToIo::to_io)?;
+        let buffer = self.buffer(path)?;
+        let mut buffer = buffer.write().map_err(PoisonError::to_io)?;
+        buffer.corrupt(offset, length, corrupt)
+    }
+
+    /// Randomly corrupts file contents as they are read. `None` disables
+    /// random corruption, but doesn't repair previously corrupted data.
+    pub fn set_bit_rot(&self, bit_rot: Option<BitRot>) {
+        *self
+            .volume
+            .bit_rot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = bit_rot;
+    }
+
+    /// Configures how [`crash()`](Self::crash) treats unsynced writes.
+    ///
+    /// By default, all unsynced writes are discarded. When `Some`, each
+    /// sector that has unsynced changes is independently either persisted or
+    /// discarded, and each file's length is either persisted or reverted. This
+    /// simulates a crash in the middle of the disk writing out a file's
+    /// contents.
+    ///
+    /// The outcome is determined by [`TornWrites::seed`], so a sequence of
+    /// operations and crashes always produces the same result.
+    pub fn set_torn_writes(&self, torn_writes: Option<TornWrites>) {
+        let mut state = self
+            .torn_writes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *state = torn_writes.map(|torn_writes| {
+            assert!(torn_writes.sector_size > 0, "sector_size must be non-zero");
+            TornWriter {
+                sector_size: torn_writes.sector_size,
+                rng: Rng::new(torn_writes.seed),
+            }
+        });
+    }
+
+    /// Simulates a crash or power loss by discarding everything that hasn't
+    /// been synced.
+    ///
+    /// Like on a real file system, creating, renaming or removing a file or
+    /// directory is only durable once its parent directory has been synced.
+    /// Directories can be synced by opening them and calling
+    /// [`File::sync_all()`], or by using [`FileManager::sync_all()`]. The
+    /// directory tree is reverted to the entries each directory contained when
+    /// it was last synced.
+    ///
+    /// Every file's contents are reverted to what they were when the file was
+    /// last synced using [`File::sync_data()`] or [`File::sync_all()`]. Files
+    /// that were never synced become empty. Unsynced writes can instead be
+    /// partially persisted using [`set_torn_writes()`](Self::set_torn_writes).
+    ///
+    /// Open files observe the reverted contents. Like after a real crash, they
+    /// should no longer be used, and the files should be reopened instead.
+    pub fn crash(&self) -> io::Result<()> {
+        let mut directories = self.directories.lock().map_err(ToIo::to_io)?;
+        let mut files = self.files.write().map_err(ToIo::to_io)?;
+        let mut torn_writes = self.torn_writes.lock().map_err(ToIo::to_io)?;
+        let root = PathId::root();
+        let Some(FileBacking::Directory(root_directory)) =
+            files.get(&root).map(|file| file.backing.clone())
+        else {
+            unreachable!("/ is always a directory")
+        };
+
+        directories.clear();
+        files.clear();
+        files.insert(
+            root.clone(),
+            MemoryFile {
+                path: root.clone(),
+                backing: FileBacking::Directory(root_directory.clone()),
+            },
+        );
+        let mut directories_to_restore = vec![(root, root_directory.durable)];
+        while let Some((path, durable)) = directories_to_restore.pop() {
+            let durable = durable.lock().map_err(ToIo::to_io)?;
+            let mut entries = HashSet::with_capacity(durable.len());
+            // Restore the entries in a consistent order, so that torn writes
+            // are reproducible.
+            let mut durable = durable.iter().collect::<Vec<_>>();
+            durable.sort_unstable_by_key(|(name, _)| *name);
+            for (name, entry) in durable {
+                let entry_path = PathId::from(path.join(name));
+                let backing = match entry {
+                    DurableEntry::File(buffer) => {
+                        let mut contents = buffer.write().map_err(PoisonError::to_io)?;
+                        if let Some(torn_writes) = &mut *torn_writes {
+                            torn_writes.tear(&mut contents);
+                        }
+                        contents.revert_to_durable();
+                        drop(contents);
+                        FileBacking::Buffer {
+                            position: Arc::default(),
+                            buffer: buffer.clone(),
+                        }
+                    }
+                    DurableEntry::Directory(durable) => {
+                        directories_to_restore.push((entry_path.clone(), durable.clone()));
+                        FileBacking::Directory(self.directory(durable.clone()))
+                    }
+                };
+                files.insert(
+                    entry_path.clone(),
+                    MemoryFile {
+                        path: entry_path.clone(),
+                        backing,
+                    },
+                );
+                entries.insert(entry_path);
+            }
+            directories.insert(path, entries);
+        }
+        Ok(())
+    }
+
+    /// Returns an independent file system containing the same directories and
+    /// files as this one.
+    ///
+    /// File contents are shared between both file systems until either side
+    /// modifies them, making snapshots cheap to create. Unsynced changes are
+    /// preserved, so [`crash()`](Self::crash) affects the snapshot the same way
+    /// it would affect this file system.
+    ///
+    /// The snapshot has the same maximum size, but none of the other simulation
+    /// settings, such as fault schedules or torn writes, are copied.
+    pub fn snapshot(&self) -> io::Result<Self> {
+        let directories = self.directories.lock().map_err(ToIo::to_io)?;
+        let files = self.files.read().map_err(ToIo::to_io)?;
+        let snapshot = Self {
+            files: Arc::default(),
+            directories: Arc::new(Mutex::new(directories.clone())),
+            volume: Arc::new(self.volume.snapshot()),
+            torn_writes: Arc::default(),
+            checkpoints: Arc::default(),
+            fsyncs: FSyncManager::default(),
+        };
+
+        let mut copier = SnapshotCopier {
+            volume: snapshot.volume.clone(),
+            buffers: HashMap::new(),
+            durable_entries: HashMap::new(),
+        };
+        let mut snapshot_files = HashMap::with_capacity(files.len());
+        for (path, file) in files.iter() {
+            let backing = match &file.backing {
+                FileBacking::Directory(directory) => FileBacking::Directory(
+                    snapshot.directory(copier.durable_entries(&directory.durable)?),
+                ),
+                FileBacking::Buffer { buffer, .. } => FileBacking::Buffer {
+                    position: Arc::default(),
+                    buffer: copier.buffer(buffer)?,
+                },
+            };
+            snapshot_files.insert(
+                path.clone(),
+                MemoryFile {
+                    path: path.clone(),
+                    backing,
+                },
+            );
+        }
+        *snapshot.files.write().map_err(ToIo::to_io)? = snapshot_files;
+
+        Ok(snapshot)
+    }
+
+    /// Saves the current state of this file system as `name`, replacing any
+    /// existing checkpoint with the same name. The state can be restored using
+    /// [`rollback_to()`](Self::rollback_to).
+    ///
+    /// Like snapshots, checkpoints share file contents until they are
+    /// modified. A checkpoint keeps the contents it refers to alive until it is
+    /// removed using [`remove_checkpoint()`](Self::remove_checkpoint).
+    pub fn checkpoint(&self, name: impl Into<String>) -> io::Result<()> {
+        let directories = self.directories.lock().map_err(ToIo::to_io)?;
+        let files = self.files.read().map_err(ToIo::to_io)?;
+        let mut checkpoint = Checkpoint {
+            directories: directories.clone(),
+            files: files.clone(),
+            buffers: HashMap::new(),
+            durable_entries: Vec::new(),
+        };
+        let mut saved = HashSet::new();
+        for file in files.values() {
+            match &file.backing {
+                FileBacking::Directory(directory) => {
+                    checkpoint.save_durable_entries(&directory.durable, &mut saved)?;
+                }
+                FileBacking::Buffer { buffer, .. } => checkpoint.save_buffer(buffer)?,
+            }
+        }
+        drop(files);
+        drop(directories);
+
+        self.checkpoints
+            .lock()
+            .map_err(ToIo::to_io)?
+            .insert(name.into(), checkpoint);
+        Ok(())
+    }
+
+    /// Restores the state saved by [`checkpoint()`](Self::checkpoint) as
+    /// `name`. The checkpoint is kept, so it can be rolled back to again.
+    ///
+    /// Files that are still open observe the rolled back contents, including
+    /// files that were removed after the checkpoint was created. Files created
+    /// after the checkpoint no longer exist. Which changes are durable is
+    /// restored as well, so [`crash()`](Self::crash) behaves as it would have
+    /// at the checkpoint. Simulation settings, such as fault schedules and bad
+    /// regions, aren't affected.
+    pub fn rollback_to(&self, name: &str) -> io::Result<()> {
+        let checkpoints = self.checkpoints.lock().map_err(ToIo::to_io)?;
+        let checkpoint = checkpoints.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("checkpoint {name:?} not found"),
+            )
+        })?;
+        let mut directories = self.directories.lock().map_err(ToIo::to_io)?;
+        let mut files = self.files.write().map_err(ToIo::to_io)?;
+
+        directories.clone_from(&checkpoint.directories);
+        files.clone_from(&checkpoint.files);
+        for (durable, entries) in &checkpoint.durable_entries {
+            durable.lock().map_err(ToIo::to_io)?.clone_from(entries);
+        }
+        for saved in checkpoint.buffers.values() {
+            let mut buffer = saved.buffer.write().map_err(PoisonError::to_io)?;
+            buffer.replace_contents(saved.bytes.clone());
+            buffer.durable = saved.durable.clone();
+            buffer.mark_all_dirty();
+        }
+        Ok(())
+    }
+
+    /// Removes the checkpoint named `name`, returning true if it existed.
+    pub fn remove_checkpoint(&self, name: &str) -> bool {
+        self.checkpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+            .is_some()
+    }
+
+    /// Copies the file or directory tree at `source_path` in `source` into
+    /// this file system at `path`. This can be used to load a directory from
+    /// disk using [`StdFileManager`](crate::fs::StdFileManager).
+    ///
+    /// Missing parent directories of `path` are created, and existing files
+    /// are overwritten. Everything imported is synced, so it survives
+    /// [`crash()`](Self::crash).
+    pub fn import<Source: FileManager>(
+        &self,
+        source: &Source,
+        source_path: &PathId,
+        path: &PathId,
+    ) -> io::Result<()> {
+        copy_tree(source, source_path, self, path)?;
+        sync_tree(self, path)?;
+        let mut parent = path.parent();
+        while let Some(directory) = parent {
+            self.sync_all(&directory)?;
+            parent = directory.parent();
+        }
+        Ok(())
+    }
+
+    /// Copies the file or directory tree at `path` in this file system to
+    /// `destination_path` in `destination`. This can be used to write a
+    /// directory to disk using [`StdFileManager`](crate::fs::StdFileManager)
+    /// for inspection.
+    ///
+    /// Missing parent directories of `destination_path` are created, and
+    /// existing files are overwritten. Unsynced changes are exported too.
+    pub fn export<Destination: FileManager>(
+        &self,
+        path: &PathId,
+        destination: &Destination,
+        destination_path: &PathId,
+    ) -> io::Result<()> {
+        copy_tree(self, path, destination, destination_path)?;
+        Ok(())
+    }
+
+    fn new_directory(&self, path: PathId) -> MemoryFile {
+        MemoryFile {
+            path,
+            backing: FileBacking::Directory(self.directory(DurableEntries::default())),
+        }
+    }
+
+    fn directory(&self, durable: DurableEntries) -> Directory {
+        Directory {
+            durable,
+            directories: Arc::downgrade(&self.directories),
+            files: Arc::downgrade(&self.files),
+            volume: self.volume.clone(),
+        }
+    }
+
+    /// Injects the faults described by `schedule` into this file system and
+    /// its files. `None` stops injecting faults.
+    ///
+    /// Replacing the schedule restarts counting calls from zero.
+    pub fn set_fault_schedule(&self, schedule: Option<FaultSchedule>) {
+        *self
+            .volume
+            .faults
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = schedule;
+    }
+
+    /// Controls what happens to a file's unsynced writes when a fault is
+    /// injected into [`File::sync_data()`] or [`File::sync_all()`].
+    ///
+    /// By default, the unsynced writes are kept and a later sync can still
+    /// persist them. When enabled, the unsynced writes are discarded, and
+    /// retrying the sync reports success without persisting them. This mirrors
+    /// how Linux handles writeback errors: the affected pages are marked clean,
+    /// so retrying `fsync()` can't detect that data was lost. Unlike Linux,
+    /// the discarded writes are immediately no longer visible to readers.
+    pub fn set_sync_failure_discards_writes(&self, enabled: bool) {
+        self.volume
+            .sync_failure_discards_writes
+            .store(enabled, atomic::Ordering::Relaxed);
+    }
+
+    /// Simulates the latencies in `profile` for this file system's operations.
+    /// `None` removes all simulated latency.
+    pub fn set_latency_profile(&self, profile: Option<LatencyProfile>) {
+        *self
+            .volume
+            .latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = profile;
+    }
+
+    /// Makes reads and writes of this file system's files transfer fewer bytes
+    /// than requested or be interrupted, as configured by `partial_io`. `None`
+    /// restores the default behavior of transferring as many bytes as
+    /// possible.
+    pub fn set_partial_io(&self, partial_io: Option<PartialIo>) {
+        *self
+            .volume
+            .partial_io
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = partial_io;
+    }
+
+    pub(crate) fn start_recording(&self) {
+        *self
+            .volume
+            .recording
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Vec::new());
+    }
+
+    pub(crate) fn finish_recording(&self) -> Vec<Operation> {
+        self.volume
+            .recording
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .unwrap_or_default()
+    }
+
+    fn buffer(&self, path: &PathId) -> io::Result<Arc<RwLock<Buffer>>> {
+        let files = self.files.read().map_err(ToIo::to_io)?;
+        match files.get(path).map(|file| &file.backing) {
+            Some(FileBacking::Buffer { buffer, .. }) => Ok(buffer.clone()),
+            Some(FileBacking::Directory(_)) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "path is a directory",
+            )),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+}
+
 impl Default for MemoryFileManager {
     fn default() -> Self {
         let root = PathId::from(PathBuf::from(MAIN_SEPARATOR.to_string()));
-        Self {
-            files: Arc::new(RwLock::new(
-                [(root.clone(), MemoryFile::new_directory(root.clone()))]
-                    .into_iter()
-                    .collect(),
+        let manager = Self {
+            files: Arc::default(),
+            directories: Arc::new(Mutex::new(
+                [(root.clone(), HashSet::new())].into_iter().collect(),
             )),
-            directories: Arc::new(Mutex::new([(root, HashSet::new())].into_iter().collect())),
+            volume: Arc::default(),
+            torn_writes: Arc::default(),
+            checkpoints: Arc::default(),
             fsyncs: FSyncManager::default(),
-        }
+        };
+        let root_directory = manager.new_directory(root.clone());
+        manager
+            .files
+            .write()
+            .expect("just created")
+            .insert(root, root_directory);
+        manager
     }
 }
 
@@ -39,6 +553,8 @@ impl FileManager for MemoryFileManager {
 
     fn open(&self, path: &PathId, options: OpenOptions) -> std::io::Result<Self::File> {
         check_path(path)?;
+        self.volume.check_fault(OperationKind::Open, path)?;
+        self.volume.simulate_latency(OperationKind::Open)?;
         let files = self.files.read().map_err(ToIo::to_io)?;
         if let Some(file) = files.get(path).map(MemoryFile::detach) {
             // TODO restrict from writing to a read-only file?
@@ -63,7 +579,13 @@ impl FileManager for MemoryFileManager {
                         // Record the directory entry.
                         parent.insert(path.clone());
                         // Create the file
-                        Ok(empty.insert(MemoryFile::new(path.clone())).detach())
+                        let file =
+                            empty.insert(MemoryFile::in_volume(path.clone(), self.volume.clone()));
+                        self.volume.record(|| Operation::CreateFile {
+                            path: path.clone(),
+                            file: file.id(),
+                        });
+                        Ok(file.detach())
                     }
                 }
             } else {
@@ -84,6 +606,10 @@ impl FileManager for MemoryFileManager {
 
     fn create_dir_all(&self, path: &PathId) -> std::io::Result<()> {
         check_path(path)?;
+        self.volume
+            .check_fault(OperationKind::CreateDirectory, path)?;
+        self.volume
+            .simulate_latency(OperationKind::CreateDirectory)?;
         let mut directories = self.directories.lock().map_err(ToIo::to_io)?;
         let mut files = self.files.write().map_err(ToIo::to_io)?;
 
@@ -100,7 +626,7 @@ impl FileManager for MemoryFileManager {
                 let mut path_to_check = Cow::Borrowed(path);
                 loop {
                     match files.get(&path_to_check) {
-                        Some(file) if matches!(file.backing, FileBacking::Directory) => break,
+                        Some(file) if matches!(file.backing, FileBacking::Directory(_)) => break,
                         Some(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists)),
                         None => {
                             let Some(next_root) = path_to_check.parent() else { unreachable!("/ always is in files") };
@@ -112,20 +638,22 @@ impl FileManager for MemoryFileManager {
 
                 // We get here only if we fine a non-file directory that exists.
                 // That means we can now create all of the directory entries
-                // requested.
-                let mut paths_to_create = paths_to_create.into_iter().peekable();
-                while let Some(path_to_create) = paths_to_create.next() {
+                // requested, starting with the one closest to the root.
+                for path_to_create in paths_to_create.into_iter().rev() {
                     let path_to_create = path_to_create.into_owned();
+                    let parent = path_to_create.parent().expect("/ always is in files");
+                    directories
+                        .get_mut(&parent)
+                        .expect("parent already created")
+                        .insert(path_to_create.clone());
                     files.insert(
                         path_to_create.clone(),
-                        MemoryFile::new_directory(path_to_create.clone()),
+                        self.new_directory(path_to_create.clone()),
                     );
-
-                    let mut files = HashSet::new();
-                    if let Some(next_file) = paths_to_create.peek().cloned() {
-                        files.insert(next_file.into_owned());
-                    }
-                    directories.insert(path_to_create, files);
+                    self.volume.record(|| Operation::CreateDirectory {
+                        path: path_to_create.clone(),
+                    });
+                    directories.insert(path_to_create, HashSet::new());
                 }
 
                 Ok(())
@@ -135,23 +663,41 @@ impl FileManager for MemoryFileManager {
 
     fn remove_dir_all(&self, path: &PathId) -> std::io::Result<()> {
         check_path(path)?;
+        self.volume
+            .check_fault(OperationKind::RemoveDirectory, path)?;
+        self.volume
+            .simulate_latency(OperationKind::RemoveDirectory)?;
         let mut directories = self.directories.lock().map_err(ToIo::to_io)?;
         let mut files = self.files.write().map_err(ToIo::to_io)?;
 
         if path.is_root() {
             // No need to scan the structures when we're removing everything.
+            // The root directory itself is kept, as its durable entries are
+            // still needed if a crash happens before it is synced.
+            let root = files.remove(path).expect("/ always is in files");
             directories.clear();
             directories.insert(path.clone(), HashSet::new());
             files.clear();
-            files.insert(path.clone(), MemoryFile::new_directory(path.clone()));
+            files.insert(path.clone(), root);
         } else {
+            if !directories.contains_key(path) {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            files.remove(path);
+            if let Some(parent) = path
+                .parent()
+                .and_then(|parent| directories.get_mut(&parent))
+            {
+                parent.remove(path);
+            }
+
             let mut directories_to_scan = vec![path.clone()];
             while let Some(directory) = directories_to_scan.pop() {
                 let Some(directory_files) = directories.remove(&directory)
                     else { return Err(io::Error::from(io::ErrorKind::NotFound)) };
                 for file in directory_files {
                     let Some(file) = files.remove(&file) else { unreachable!("file missing") };
-                    if let FileBacking::Directory = file.backing {
+                    if let FileBacking::Directory(_) = file.backing {
                         // This file was a directory itself. We need to remove its
                         // contents as well.
                         directories_to_scan.push(file.path);
@@ -159,11 +705,15 @@ impl FileManager for MemoryFileManager {
                 }
             }
         }
+        self.volume
+            .record(|| Operation::RemoveDirectory { path: path.clone() });
         Ok(())
     }
 
     fn remove_file(&self, path: &PathId) -> std::io::Result<()> {
         check_path(path)?;
+        self.volume.check_fault(OperationKind::RemoveFile, path)?;
+        self.volume.simulate_latency(OperationKind::RemoveFile)?;
         let mut directories = self.directories.lock().map_err(ToIo::to_io)?;
         let mut files = self.files.write().map_err(ToIo::to_io)?;
         if let Some(parent) = path.parent() {
@@ -172,6 +722,8 @@ impl FileManager for MemoryFileManager {
                     .get_mut(&parent)
                     .expect("file exists without directory")
                     .remove(path);
+                self.volume
+                    .record(|| Operation::RemoveFile { path: path.clone() });
                 Ok(())
             } else {
                 Err(io::Error::from(io::ErrorKind::NotFound))
@@ -192,9 +744,11 @@ impl FileManager for MemoryFileManager {
     }
 
     fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        self.volume.check_fault(OperationKind::List, path)?;
+        self.volume.simulate_latency(OperationKind::List)?;
         let directories = self.directories.lock().map_err(ToIo::to_io)?;
         if let Some(contents) = directories.get(path) {
-            Ok(dbg!(contents.iter().cloned().collect()))
+            Ok(contents.iter().cloned().collect())
         } else {
             Err(io::Error::from(io::ErrorKind::NotFound))
         }
@@ -203,27 +757,36 @@ impl FileManager for MemoryFileManager {
     fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
         check_path(from)?;
         check_path(&to)?;
+        self.volume.check_fault(OperationKind::Rename, from)?;
+        self.volume.simulate_latency(OperationKind::Rename)?;
 
-        let Some(from_parent) = from.parent()
-            else {
-                return Err(io::Error::from(io::ErrorKind::Unsupported))
-            };
+        let (Some(from_parent), Some(to_parent)) = (from.parent(), to.parent()) else {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        };
 
         let mut directories = self.directories.lock().map_err(ToIo::to_io)?;
         let mut files = self.files.write().map_err(ToIo::to_io)?;
-        if let Some(mut original) = files.remove(from) {
-            original.path = to.clone();
-            let directory = directories
-                .get_mut(&from_parent)
-                .expect("missing directory");
-            directory.remove(from);
-            directory.insert(to.clone());
-            files.insert(to, original);
-
-            Ok(())
-        } else {
-            Err(io::Error::from(io::ErrorKind::NotFound))
+        if !files.contains_key(from) || !directories.contains_key(&to_parent) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
         }
+
+        let mut original = files.remove(from).expect("checked above");
+        original.path = to.clone();
+        directories
+            .get_mut(&from_parent)
+            .expect("missing directory")
+            .remove(from);
+        directories
+            .get_mut(&to_parent)
+            .expect("checked above")
+            .insert(to.clone());
+        self.volume.record(|| Operation::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        });
+        files.insert(to, original);
+
+        Ok(())
     }
 }
 
@@ -235,27 +798,88 @@ pub struct MemoryFile {
 
 impl MemoryFile {
     pub fn new(path: PathId) -> Self {
+        Self::in_volume(path, Arc::default())
+    }
+
+    pub fn new_directory(path: PathId) -> Self {
+        Self {
+            path,
+            backing: FileBacking::Directory(Directory {
+                durable: DurableEntries::default(),
+                directories: Weak::new(),
+                files: Weak::new(),
+                volume: Arc::default(),
+            }),
+        }
+    }
+
+    fn in_volume(path: PathId, volume: Arc<Volume>) -> Self {
         Self {
             path,
             backing: FileBacking::Buffer {
-                buffer: Arc::default(),
+                buffer: Arc::new(RwLock::new(Buffer {
+                    id: volume.next_file_id.fetch_add(1, atomic::Ordering::Relaxed),
+                    bytes: Arc::default(),
+                    durable: Arc::default(),
+                    dirty: Vec::new(),
+                    bad_regions: Vec::new(),
+                    volume,
+                })),
                 position: Arc::default(),
             },
         }
     }
 
-    pub fn new_directory(path: PathId) -> Self {
-        Self {
-            path,
-            backing: FileBacking::Directory,
+    /// Returns the unique id of this file's contents. Directories have no id.
+    fn id(&self) -> u64 {
+        match &self.backing {
+            FileBacking::Buffer { buffer, .. } => {
+                buffer.read().unwrap_or_else(PoisonError::into_inner).id
+            }
+            FileBacking::Directory(_) => unreachable!("directories have no id"),
         }
     }
 
+    fn sync(&self, operation: OperationKind) -> io::Result<()> {
+        self.simulate_latency(operation)?;
+        match &self.backing {
+            FileBacking::Directory(directory) => {
+                directory.volume.check_fault(operation, &self.path)?;
+                directory.sync(&self.path)
+            }
+            FileBacking::Buffer { buffer, .. } => {
+                let mut buffer = buffer.write().map_err(PoisonError::to_io)?;
+                if let Err(err) = buffer.volume.check_fault(operation, &self.path) {
+                    if buffer
+                        .volume
+                        .sync_failure_discards_writes
+                        .load(atomic::Ordering::Relaxed)
+                    {
+                        buffer.revert_to_durable();
+                    }
+                    return Err(err);
+                }
+                buffer.sync();
+                Ok(())
+            }
+        }
+    }
+
+    fn simulate_latency(&self, operation: OperationKind) -> io::Result<()> {
+        let volume = match &self.backing {
+            FileBacking::Directory(directory) => directory.volume.clone(),
+            FileBacking::Buffer { buffer, .. } => {
+                buffer.read().map_err(PoisonError::to_io)?.volume.clone()
+            }
+        };
+        volume.simulate_latency(operation)
+    }
+
     fn detach(&self) -> Self {
         Self {
             path: self.path.clone(),
             backing: match &self.backing {
-                FileBacking::Directory => FileBacking::Directory,
+                FileBacking::Directory(directory) => FileBacking::Directory(directory.clone()),
                 FileBacking::Buffer { buffer, .. } => FileBacking::Buffer {
                     position: Arc::default(),
                     buffer: buffer.clone(),
@@ -273,30 +897,38 @@ impl File for MemoryFile {
     }
 
     fn sync_all(&self) -> std::io::Result<()> {
-        Ok(())
+        self.sync(OperationKind::SyncAll)
     }
 
     fn sync_data(&self) -> std::io::Result<()> {
-        Ok(())
+        self.sync(OperationKind::SyncData)
     }
 
     fn len(&self) -> io::Result<u64> {
         match &self.backing {
-            FileBacking::Directory => Ok(0),
+            FileBacking::Directory(_) => Ok(0),
             FileBacking::Buffer { buffer, .. } => {
                 let buffer = buffer.read().map_err(PoisonError::to_io)?;
-                Ok(buffer.len() as u64)
+                Ok(buffer.bytes.len() as u64)
             }
         }
     }
 
     fn set_len(&self, new_length: u64) -> io::Result<()> {
+        self.simulate_latency(OperationKind::SetLength)?;
         match &self.backing {
-            FileBacking::Directory => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            FileBacking::Directory(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
             FileBacking::Buffer { buffer, position } => {
                 let mut buffer = buffer.write().map_err(PoisonError::to_io)?;
+                buffer
+                    .volume
+                    .check_fault(OperationKind::SetLength, &self.path)?;
                 let new_length = new_length.try_into().map_err(ToIo::to_io)?;
-                buffer.resize(new_length, 0);
+                buffer.resize(new_length)?;
+                buffer.volume.record(|| Operation::SetLength {
+                    file: buffer.id,
+                    length: new_length as u64,
+                });
                 drop(buffer);
                 let mut position = position.lock().map_err(PoisonError::to_io)?;
                 if *position > new_length {
@@ -314,21 +946,40 @@ impl File for MemoryFile {
 
 impl Read for MemoryFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.simulate_latency(OperationKind::Read)?;
         match &self.backing {
-            FileBacking::Directory => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            FileBacking::Directory(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
             FileBacking::Buffer { buffer, position } => {
                 let mut position = position.lock().map_err(PoisonError::to_io)?;
-                let buffer = buffer.read().map_err(PoisonError::to_io)?;
+                let contents = buffer.read().map_err(PoisonError::to_io)?;
+                contents
+                    .volume
+                    .check_fault(OperationKind::Read, &self.path)?;
+                let bytes_allowed = contents.volume.limit_io(buf.len())?;
+                let buf = &mut buf[..bytes_allowed];
 
-                if let Some(bytes_available) = buffer.len().checked_sub(*position) {
-                    let bytes_to_read = bytes_available.min(buf.len());
-                    let read_end = *position + bytes_to_read;
-                    buf[..bytes_to_read].copy_from_slice(&buffer[*position..read_end]);
-                    *position = read_end;
-                    Ok(bytes_to_read)
-                } else {
-                    Ok(0)
+                let Some(bytes_available) = contents.bytes.len().checked_sub(*position) else {
+                    return Ok(0);
+                };
+                let bytes_to_read = bytes_available.min(buf.len());
+                let read_end = *position + bytes_to_read;
+                contents.check_bad_regions(*position..read_end, RegionAccess::Read)?;
+                buf[..bytes_to_read].copy_from_slice(&contents.bytes[*position..read_end]);
+                let bit_rot = contents.volume.sample_bit_rot(&self.path, bytes_to_read)?;
+                drop(contents);
+
+                if let Some((offset, mask)) = bit_rot {
+                    buf[offset] ^= mask;
+                    // The rot is permanent, which requires the write lock.
+                    // Another handle may have shrunk the file after the read
+                    // lock was released.
+                    let mut contents = buffer.write().map_err(PoisonError::to_io)?;
+                    if *position + offset < contents.bytes.len() {
+                        contents.corrupt(*position + offset, 1, |_, byte| byte ^ mask)?;
+                    }
                 }
+                *position = read_end;
+                Ok(bytes_to_read)
             }
         }
     }
@@ -336,44 +987,55 @@ impl Read for MemoryFile {
 
 impl Write for MemoryFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.simulate_latency(OperationKind::Write)?;
         match &self.backing {
-            FileBacking::Directory => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            FileBacking::Directory(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
             FileBacking::Buffer { buffer, position } => {
                 let mut position = position.lock().map_err(PoisonError::to_io)?;
                 let mut buffer = buffer.write().map_err(PoisonError::to_io)?;
-                let buffer_length = buffer.len();
-
-                match position.cmp(&buffer_length) {
-                    Ordering::Greater => {
-                        // Writing beyond the end of the file, fill with 0s.
-                        buffer.resize(*position, 0);
-                        buffer.extend_from_slice(buf);
-                        *position = buffer.len();
-                        Ok(buf.len())
-                    }
-                    Ordering::Equal => {
-                        // Writing at the end of the file, but no neeed to fill.
-                        buffer.extend_from_slice(buf);
-                        *position = buffer.len();
-                        Ok(buf.len())
-                    }
-                    Ordering::Less => {
-                        // Writing inside of the file.
-                        let bytes_from_end = buffer_length - *position;
-                        if bytes_from_end > 0 {
-                            // Fill the buffer.
-                            let bytes_to_write = bytes_from_end.min(buf.len());
-                            let write_end = bytes_to_write + *position;
-                            buffer[*position..write_end].copy_from_slice(&buf[..bytes_to_write]);
-                            *position = write_end;
-                            Ok(bytes_to_write)
-                        } else {
-                            buffer.extend_from_slice(buf);
-                            *position += buf.len();
-                            Ok(buf.len())
-                        }
-                    }
+                buffer
+                    .volume
+                    .check_fault(OperationKind::Write, &self.path)?;
+                let bytes_allowed = buffer.volume.limit_io(buf.len())?;
+                let buf = &buf[..bytes_allowed];
+
+                let original_length = buffer.bytes.len();
+                let bytes_to_write = if *position < buffer.bytes.len() {
+                    // Writing inside of the file. Only the existing bytes are
+                    // overwritten.
+                    (buffer.bytes.len() - *position).min(buf.len())
+                } else {
+                    // Writing at or beyond the end of the file. Any gap is
+                    // filled with 0s.
+                    buffer.grow_for_write(*position, buf.len())?
+                };
+
+                let write_end = *position + bytes_to_write;
+                if let Err(err) =
+                    buffer.check_bad_regions(*position..write_end, RegionAccess::Write)
+                {
+                    // The write failed, so the file must not have grown.
+                    buffer.resize(original_length)?;
+                    return Err(err);
                 }
+                Arc::make_mut(&mut buffer.bytes)[*position..write_end]
+                    .copy_from_slice(&buf[..bytes_to_write]);
+                buffer.mark_dirty(*position..write_end);
+                if bytes_to_write > 0 {
+                    buffer.volume.record(|| Operation::Write {
+                        file: buffer.id,
+                        offset: *position as u64,
+                        bytes: buf[..bytes_to_write].to_vec(),
+                    });
+                } else if buffer.bytes.len() != original_length {
+                    // Only the gap before the position was filled.
+                    buffer.volume.record(|| Operation::SetLength {
+                        file: buffer.id,
+                        length: buffer.bytes.len() as u64,
+                    });
+                }
+                *position = write_end;
+                Ok(bytes_to_write)
             }
         }
     }
@@ -386,14 +1048,14 @@ impl Write for MemoryFile {
 impl Seek for MemoryFile {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
         match &self.backing {
-            FileBacking::Directory => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            FileBacking::Directory(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
             FileBacking::Buffer { buffer, position } => {
                 let mut position = position.lock().map_err(PoisonError::to_io)?;
                 let new_position = match pos {
                     io::SeekFrom::Start(offset) => offset,
                     io::SeekFrom::End(from_end) => {
                         let buffer = buffer.read().map_err(PoisonError::to_io)?;
-                        let current_length = buffer.len() as u64;
+                        let current_length = buffer.bytes.len() as u64;
                         if from_end > 0 {
                             current_length + from_end as u64
                         } else if from_end == i64::MIN {
@@ -421,35 +1083,602 @@ impl Seek for MemoryFile {
     }
 }
 
+type Directories = Mutex<HashMap<PathId, HashSet<PathId>>>;
+type Files = RwLock<HashMap<PathId, MemoryFile>>;
+
 #[derive(Clone, Debug)]
 enum FileBacking {
-    Directory,
+    Directory(Directory),
     Buffer {
         /// The position within the file's buffer. Always lock this before the
         /// buffer if both need to be locked.
         position: Arc<Mutex<usize>>,
         /// The file's buffer. Always lock this after the position, if both need
         /// to be locked.
-        buffer: Arc<RwLock<Vec<u8>>>,
+        buffer: Arc<RwLock<Buffer>>,
     },
 }
 
+#[derive(Clone, Debug)]
+struct Directory {
+    /// The entries of this directory as of the last time it was synced. These
+    /// are the entries that survive a crash.
+    durable: DurableEntries,
+    /// The file system this directory belongs to. These are weak references,
+    /// as the file system owns this directory.
+    directories: Weak<Directories>,
+    files: Weak<Files>,
+    volume: Arc<Volume>,
+}
+
+impl Directory {
+    /// Makes the current entries of the directory at `path` durable.
+    fn sync(&self, path: &PathId) -> io::Result<()> {
+        let (Some(directories), Some(files)) = (self.directories.upgrade(), self.files.upgrade())
+        else {
+            // The file system no longer exists.
+            return Ok(());
+        };
+        let directories = directories.lock().map_err(ToIo::to_io)?;
+        let files = files.read().map_err(ToIo::to_io)?;
+        match files.get(path).map(|file| &file.backing) {
+            Some(FileBacking::Directory(directory))
+                if Arc::ptr_eq(&directory.durable, &self.durable) => {}
+            // This directory has been removed, so there are no entries to sync.
+            _ => return Ok(()),
+        }
+
+        let mut durable = HashMap::new();
+        for entry_path in directories.get(path).into_iter().flatten() {
+            let name = entry_path
+                .file_name()
+                .expect("only / has no file name")
+                .to_os_string();
+            let entry = match &files[entry_path].backing {
+                FileBacking::Directory(directory) => {
+                    DurableEntry::Directory(directory.durable.clone())
+                }
+                FileBacking::Buffer { buffer, .. } => DurableEntry::File(buffer.clone()),
+            };
+            durable.insert(name, entry);
+        }
+        *self.durable.lock().map_err(ToIo::to_io)? = durable;
+        self.volume
+            .record(|| Operation::SyncDirectory { path: path.clone() });
+
+        Ok(())
+    }
+}
+
+type DurableEntries = Arc<Mutex<HashMap<OsString, DurableEntry>>>;
+
+#[derive(Clone, Debug)]
+enum DurableEntry {
+    File(Arc<RwLock<Buffer>>),
+    Directory(DurableEntries),
+}
+
+/// A state saved by [`MemoryFileManager::checkpoint()`].
+#[derive(Debug)]
+struct Checkpoint {
+    directories: HashMap<PathId, HashSet<PathId>>,
+    files: HashMap<PathId, MemoryFile>,
+    /// Every buffer referenced by `files` or by durable entries, by id.
+    buffers: HashMap<u64, SavedBuffer>,
+    /// Every directory's durable entries, including directories that are only
+    /// referenced by other durable entries.
+    durable_entries: Vec<(DurableEntries, HashMap<OsString, DurableEntry>)>,
+}
+
+impl Checkpoint {
+    fn save_buffer(&mut self, buffer: &Arc<RwLock<Buffer>>) -> io::Result<()> {
+        let contents = buffer.read().map_err(PoisonError::to_io)?;
+        self.buffers
+            .entry(contents.id)
+            .or_insert_with(|| SavedBuffer {
+                buffer: buffer.clone(),
+                bytes: contents.bytes.clone(),
+                durable: contents.durable.clone(),
+            });
+        Ok(())
+    }
+
+    fn save_durable_entries(
+        &mut self,
+        durable: &DurableEntries,
+        saved: &mut HashSet<*const Mutex<HashMap<OsString, DurableEntry>>>,
+    ) -> io::Result<()> {
+        if !saved.insert(Arc::as_ptr(durable)) {
+            return Ok(());
+        }
+
+        let entries = durable.lock().map_err(ToIo::to_io)?.clone();
+        for entry in entries.values() {
+            match entry {
+                DurableEntry::File(buffer) => self.save_buffer(buffer)?,
+                DurableEntry::Directory(durable) => self.save_durable_entries(durable, saved)?,
+            }
+        }
+        self.durable_entries.push((durable.clone(), entries));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SavedBuffer {
+    buffer: Arc<RwLock<Buffer>>,
+    bytes: Arc<Vec<u8>>,
+    durable: Arc<Vec<u8>>,
+}
+
+/// Copies buffers and durable entries into a snapshot's volume. Each buffer
+/// and set of durable entries is copied once, so that entries sharing them in
+/// the original file system also share them in the snapshot.
+struct SnapshotCopier {
+    volume: Arc<Volume>,
+    buffers: HashMap<*const RwLock<Buffer>, Arc<RwLock<Buffer>>>,
+    durable_entries: HashMap<*const Mutex<HashMap<OsString, DurableEntry>>, DurableEntries>,
+}
+
+impl SnapshotCopier {
+    fn buffer(&mut self, original: &Arc<RwLock<Buffer>>) -> io::Result<Arc<RwLock<Buffer>>> {
+        match self.buffers.entry(Arc::as_ptr(original)) {
+            hash_map::Entry::Occupied(copy) => Ok(copy.get().clone()),
+            hash_map::Entry::Vacant(entry) => {
+                let original = original.read().map_err(PoisonError::to_io)?;
+                // The copy releases its space when dropped, so it must be
+                // accounted for in the snapshot's storage.
+                self.volume
+                    .storage
+                    .force_reserve(original.bytes.len() as u64);
+                let copy = Arc::new(RwLock::new(Buffer {
+                    id: original.id,
+                    bytes: original.bytes.clone(),
+                    durable: original.durable.clone(),
+                    dirty: original.dirty.clone(),
+                    bad_regions: original.bad_regions.clone(),
+                    volume: self.volume.clone(),
+                }));
+                Ok(entry.insert(copy).clone())
+            }
+        }
+    }
+
+    fn durable_entries(&mut self, original: &DurableEntries) -> io::Result<DurableEntries> {
+        if let Some(copy) = self.durable_entries.get(&Arc::as_ptr(original)) {
+            return Ok(copy.clone());
+        }
+
+        let original_entries = original.lock().map_err(ToIo::to_io)?;
+        let mut entries = HashMap::with_capacity(original_entries.len());
+        for (name, entry) in original_entries.iter() {
+            let entry = match entry {
+                DurableEntry::File(buffer) => DurableEntry::File(self.buffer(buffer)?),
+                DurableEntry::Directory(durable) => {
+                    DurableEntry::Directory(self.durable_entries(durable)?)
+                }
+            };
+            entries.insert(name.clone(), entry);
+        }
+        let copy = Arc::new(Mutex::new(entries));
+        self.durable_entries
+            .insert(Arc::as_ptr(original), copy.clone());
+        Ok(copy)
+    }
+}
+
+/// The contents of a file. The space used by the contents is tracked in
+/// [`Storage`] until the buffer is dropped.
+#[derive(Debug)]
+struct Buffer {
+    /// Uniquely identifies this file within its volume.
+    id: u64,
+    /// The contents as seen by readers, including writes that haven't been
+    /// synced yet. The contents are shared with snapshots and checkpoints
+    /// until they are modified.
+    bytes: Arc<Vec<u8>>,
+    /// The contents as of the last sync. This is what survives a crash.
+    durable: Arc<Vec<u8>>,
+    /// The ranges of `bytes` that may differ from `durable`, including any
+    /// bytes added or removed by changing the length. Syncing and reverting
+    /// only copy these ranges, rather than the whole file.
+    dirty: Vec<Range<usize>>,
+    bad_regions: Vec<BadRegion>,
+    volume: Arc<Volume>,
+}
+
+impl Buffer {
+    fn sync(&mut self) {
+        if !self.dirty.is_empty() {
+            let durable = Arc::make_mut(&mut self.durable);
+            durable.resize(self.bytes.len(), 0);
+            for range in self.dirty.drain(..) {
+                let range = range.start.min(durable.len())..range.end.min(durable.len());
+                durable[range.clone()].copy_from_slice(&self.bytes[range]);
+            }
+        }
+        self.volume.record(|| Operation::SyncFile { file: self.id });
+    }
+
+    /// Discards all unsynced changes.
+    fn revert_to_durable(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+
+        self.account_for_length(self.durable.len());
+        let bytes = Arc::make_mut(&mut self.bytes);
+        bytes.resize(self.durable.len(), 0);
+        for range in self.dirty.drain(..) {
+            let range = range.start.min(bytes.len())..range.end.min(bytes.len());
+            bytes[range.clone()].copy_from_slice(&self.durable[range]);
+        }
+    }
+
+    /// Notes that `range` may no longer match the durable contents.
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        // Sequential writes extend the previous range rather than adding one.
+        match self.dirty.last_mut() {
+            Some(last) if last.start <= range.end && range.start <= last.end => {
+                last.start = last.start.min(range.start);
+                last.end = last.end.max(range.end);
+            }
+            _ => self.dirty.push(range),
+        }
+    }
+
+    /// Notes that any part of the contents may no longer match the durable
+    /// contents.
+    fn mark_all_dirty(&mut self) {
+        self.dirty.clear();
+        self.dirty.push(0..usize::MAX);
+    }
+
+    /// Replaces the current contents with previously stored contents. The
+    /// storage limit isn't enforced, as the contents were already stored.
+    fn replace_contents(&mut self, bytes: Arc<Vec<u8>>) {
+        self.account_for_length(bytes.len());
+        self.bytes = bytes;
+    }
+
+    /// Updates the storage used for the contents to be `new_length` bytes
+    /// long, without enforcing the storage limit.
+    fn account_for_length(&self, new_length: usize) {
+        let (current_length, new_length) = (self.bytes.len() as u64, new_length as u64);
+        if new_length > current_length {
+            self.volume
+                .storage
+                .force_reserve(new_length - current_length);
+        } else {
+            self.volume.storage.release(current_length - new_length);
+        }
+    }
+
+    /// Replaces each byte in `offset..offset + length` of both the current and
+    /// durable contents with the result of `corrupt`, which is given the
+    /// index relative to `offset` and the existing byte.
+    fn corrupt(
+        &mut self,
+        offset: usize,
+        length: usize,
+        mut corrupt: impl FnMut(usize, u8) -> u8,
+    ) -> io::Result<()> {
+        if offset
+            .checked_add(length)
+            .is_none_or(|end| end > self.bytes.len())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "corruption extends beyond the end of the file",
+            ));
+        }
+
+        let bytes = Arc::make_mut(&mut self.bytes);
+        let durable = Arc::make_mut(&mut self.durable);
+        for index in 0..length {
+            let byte = &mut bytes[offset + index];
+            *byte = corrupt(index, *byte);
+            if let Some(byte) = durable.get_mut(offset + index) {
+                *byte = corrupt(index, *byte);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_bad_regions(&self, range: Range<usize>, access: RegionAccess) -> io::Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let range = range.start as u64..range.end as u64;
+        for bad in &self.bad_regions {
+            if bad.access.includes(access)
+                && bad.region.start < range.end
+                && range.start < bad.region.end
+            {
+                return Err(io::Error::new(
+                    bad.kind,
+                    format!("bad region at {}..{}", bad.region.start, bad.region.end),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resize(&mut self, new_length: usize) -> io::Result<()> {
+        let current_length = self.bytes.len();
+        if new_length > current_length {
+            self.volume
+                .storage
+                .reserve((new_length - current_length) as u64)?;
+        } else {
+            self.volume
+                .storage
+                .release((current_length - new_length) as u64);
+        }
+        Arc::make_mut(&mut self.bytes).resize(new_length, 0);
+        self.mark_dirty(current_length.min(new_length)..current_length.max(new_length));
+        Ok(())
+    }
+
+    /// Grows the buffer to fit writing up to `length` bytes at `position`,
+    /// which must be at or beyond the end of the buffer. Returns the number of
+    /// bytes that fit, which is only less than `length` when there isn't
+    /// enough space remaining.
+    fn grow_for_write(&mut self, position: usize, length: usize) -> io::Result<usize> {
+        let gap = (position - self.bytes.len()) as u64;
+        let length = length as u64;
+        let reserved = self.volume.storage.reserve_up_to(gap + length);
+        if reserved < gap || (reserved == gap && length > 0) {
+            self.volume.storage.release(reserved);
+            return Err(io::Error::from(io::ErrorKind::StorageFull));
+        }
+
+        // The bytes were reserved above, so they can't fail to fit.
+        let current_length = self.bytes.len();
+        let new_length = position + (reserved - gap) as usize;
+        Arc::make_mut(&mut self.bytes).resize(new_length, 0);
+        self.mark_dirty(current_length..new_length);
+        Ok((reserved - gap) as usize)
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.volume.storage.release(self.bytes.len() as u64);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct BadRegion {
+    region: Range<u64>,
+    access: RegionAccess,
+    kind: io::ErrorKind,
+}
+
+/// The kinds of access a bad region of a [`MemoryFile`] affects.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegionAccess {
+    /// Reads touching the region fail.
+    Read,
+    /// Writes touching the region fail.
+    Write,
+    /// Both reads and writes touching the region fail.
+    ReadWrite,
+}
+
+impl RegionAccess {
+    fn includes(self, access: RegionAccess) -> bool {
+        self == access || self == RegionAccess::ReadWrite
+    }
+}
+
+/// Configures torn writes for [`MemoryFileManager::set_torn_writes()`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TornWrites {
+    /// The unit, in bytes, that is either completely persisted or completely
+    /// discarded. Typical values are 512 and 4096.
+    pub sector_size: usize,
+    /// The seed that determines which sectors are persisted.
+    pub seed: u64,
+}
+
+#[derive(Debug)]
+struct TornWriter {
+    sector_size: usize,
+    rng: Rng,
+}
+
+impl TornWriter {
+    /// Persists a random selection of the unsynced sectors of `buffer`.
+    fn tear(&mut self, buffer: &mut Buffer) {
+        if buffer.dirty.is_empty() {
+            return;
+        }
+
+        let length = if self.rng.next_bool() {
+            buffer.bytes.len()
+        } else {
+            buffer.durable.len()
+        };
+        let mut torn = Vec::with_capacity(length);
+        for start in (0..length).step_by(self.sector_size) {
+            let end = (start + self.sector_size).min(length);
+            let current = buffer.bytes.get(start..end.min(buffer.bytes.len()));
+            let durable = buffer.durable.get(start..end.min(buffer.durable.len()));
+            let sector = if current != durable && self.rng.next_bool() {
+                current
+            } else {
+                durable
+            };
+            torn.extend_from_slice(sector.unwrap_or_default());
+            // Bytes beyond the end of the persisted contents read as zeros.
+            torn.resize(end, 0);
+        }
+        buffer.durable = Arc::new(torn);
+    }
+}
+
+/// State shared by a [`MemoryFileManager`] and all of its files.
+#[derive(Debug, Default)]
+struct Volume {
+    storage: Storage,
+    next_file_id: AtomicU64,
+    /// When `Some`, every mutating operation is recorded.
+    recording: Mutex<Option<Vec<Operation>>>,
+    faults: Mutex<Option<FaultSchedule>>,
+    /// When true, a file's unsynced writes are discarded when syncing it fails.
+    sync_failure_discards_writes: AtomicBool,
+    partial_io: Mutex<Option<PartialIo>>,
+    latencies: Mutex<Option<LatencyProfile>>,
+    bit_rot: Mutex<Option<BitRot>>,
+}
+
+impl Volume {
+    /// Returns a new volume with the same storage limit and file ids. The
+    /// storage used is accounted for as files are copied into the snapshot.
+    fn snapshot(&self) -> Self {
+        Self {
+            storage: Storage {
+                maximum: AtomicU64::new(self.storage.maximum.load(atomic::Ordering::Relaxed)),
+                used: AtomicU64::new(0),
+            },
+            next_file_id: AtomicU64::new(self.next_file_id.load(atomic::Ordering::Relaxed)),
+            ..Self::default()
+        }
+    }
+
+    /// Sleeps for the configured latency of `operation`. This must be called
+    /// without holding any locks.
+    fn simulate_latency(&self, operation: OperationKind) -> io::Result<()> {
+        let mut latencies = self.latencies.lock().map_err(ToIo::to_io)?;
+        let latency = latencies
+            .as_mut()
+            .map_or(Duration::ZERO, |latencies| latencies.sample(operation));
+        drop(latencies);
+
+        if !latency.is_zero() {
+            thread::sleep(latency);
+        }
+        Ok(())
+    }
+
+    /// Returns how many of the `requested` bytes a read or write should
+    /// transfer.
+    fn limit_io(&self, requested: usize) -> io::Result<usize> {
+        let mut partial_io = self.partial_io.lock().map_err(ToIo::to_io)?;
+        if let Some(partial_io) = &mut *partial_io {
+            partial_io.limit(requested)
+        } else {
+            Ok(requested)
+        }
+    }
+
+    /// Returns the offset and mask of a bit to flip in a read of `length`
+    /// bytes from `path`, if bit rot should corrupt the read.
+    fn sample_bit_rot(&self, path: &PathId, length: usize) -> io::Result<Option<(usize, u8)>> {
+        let mut bit_rot = self.bit_rot.lock().map_err(ToIo::to_io)?;
+        Ok(bit_rot
+            .as_mut()
+            .and_then(|bit_rot| bit_rot.sample(path, length)))
+    }
+
+    fn check_fault(&self, operation: OperationKind, path: &PathId) -> io::Result<()> {
+        let mut faults = self.faults.lock().map_err(ToIo::to_io)?;
+        if let Some(faults) = &mut *faults {
+            faults.check(operation, path)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records an operation if recording is enabled. To ensure operations are
+    /// recorded in the order they are observed, this should be called while
+    /// holding the locks required to perform the operation.
+    fn record(&self, operation: impl FnOnce() -> Operation) {
+        let mut recording = self
+            .recording
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(recording) = &mut *recording {
+            recording.push(operation());
+        }
+    }
+}
+
+/// Tracks the space used by all files of a [`MemoryFileManager`].
+#[derive(Debug)]
+struct Storage {
+    /// The maximum number of bytes that can be used. `u64::MAX` means there is
+    /// no limit.
+    maximum: AtomicU64,
+    used: AtomicU64,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            maximum: AtomicU64::new(u64::MAX),
+            used: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Storage {
+    fn reserve(&self, bytes: u64) -> io::Result<()> {
+        let reserved = self.reserve_up_to(bytes);
+        if reserved == bytes {
+            Ok(())
+        } else {
+            self.release(reserved);
+            Err(io::Error::from(io::ErrorKind::StorageFull))
+        }
+    }
+
+    /// Reserves as many of `bytes` as are available, returning the number of
+    /// bytes reserved.
+    fn reserve_up_to(&self, bytes: u64) -> u64 {
+        let maximum = self.maximum.load(atomic::Ordering::Relaxed);
+        let mut reserved = 0;
+        // The closure never returns None, so this can't fail.
+        let _result = self.used.fetch_update(
+            atomic::Ordering::AcqRel,
+            atomic::Ordering::Acquire,
+            |used| {
+                reserved = maximum.saturating_sub(used).min(bytes);
+                Some(used + reserved)
+            },
+        );
+        reserved
+    }
+
+    fn force_reserve(&self, bytes: u64) {
+        self.used.fetch_add(bytes, atomic::Ordering::AcqRel);
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, atomic::Ordering::AcqRel);
+    }
+}
+
 trait ToIo {
     fn to_io(self) -> io::Error;
 }
 
 impl<T> ToIo for PoisonError<T> {
     fn to_io(self) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, "lock poisoned")
+        io::Error::other("lock poisoned")
     }
 }
 
 impl ToIo for TryFromIntError {
     fn to_io(self) -> io::Error {
-        io::Error::new(
-            io::ErrorKind::Other,
-            "position too large for current platform",
-        )
+        io::Error::other("position too large for current platform")
     }
 }
 
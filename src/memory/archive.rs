@@ -0,0 +1,138 @@
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+use std::sync::{Arc, PoisonError};
+
+use tar::{Archive, Builder, EntryType, Header};
+
+use super::{FileBacking, MemoryFileManager, ToIo};
+use crate::tree::sync_tree;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+impl MemoryFileManager {
+    /// Writes every directory and file in this file system to `writer` as a
+    /// tar archive. The archive can be loaded using
+    /// [`load_archive()`](Self::load_archive) or inspected using standard
+    /// tools.
+    ///
+    /// The current contents of each file are saved, including unsynced
+    /// changes, but not which changes were unsynced: loading the archive
+    /// makes everything durable. Per-file state, such as bad regions, and
+    /// simulation settings, such as the maximum size or fault schedules,
+    /// aren't saved either. Entries are written in a consistent order
+    /// without timestamps or owners, so the same file system always produces
+    /// the same archive.
+    pub fn save_archive<W: Write>(&self, writer: W) -> io::Result<W> {
+        let mut entries = {
+            let files = self.files.read().map_err(ToIo::to_io)?;
+            files
+                .iter()
+                .filter(|(path, _)| !path.is_root())
+                .map(|(path, file)| match &file.backing {
+                    FileBacking::Directory(_) => Ok((path.clone(), None)),
+                    FileBacking::Buffer { buffer, .. } => {
+                        let buffer = buffer.read().map_err(PoisonError::to_io)?;
+                        Ok((path.clone(), Some(Arc::clone(&buffer.bytes))))
+                    }
+                })
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        // Sorting by path places each directory before its contents.
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut builder = Builder::new(writer);
+        for (path, contents) in entries {
+            let relative = path
+                .strip_prefix(&*PathId::root())
+                .expect("all paths are absolute");
+            let mut header = Header::new_gnu();
+            header.set_mtime(0);
+            if let Some(contents) = contents {
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(contents.len() as u64);
+                builder.append_data(&mut header, relative, contents.as_slice())?;
+            } else {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, relative, io::empty())?;
+            }
+        }
+        builder.into_inner()
+    }
+
+    /// Returns a new file system containing the directories and files of the
+    /// tar archive read from `reader`, such as one written by
+    /// [`save_archive()`](Self::save_archive).
+    ///
+    /// Everything loaded is synced, so it survives [`crash()`](Self::crash).
+    /// If a file appears more than once, its last entry's contents are kept.
+    /// Metadata-only entries, such as pax headers, are skipped. Archives
+    /// containing other entries besides directories and regular files, or
+    /// paths that escape the archive's root, are rejected with
+    /// [`io::ErrorKind::InvalidData`].
+    pub fn load_archive<R: Read>(reader: R) -> io::Result<Self> {
+        let manager = Self::default();
+        let mut archive = Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_type = entry.header().entry_type();
+            if matches!(
+                entry_type,
+                EntryType::XGlobalHeader
+                    | EntryType::XHeader
+                    | EntryType::GNULongName
+                    | EntryType::GNULongLink
+            ) {
+                // These only describe other entries.
+                continue;
+            }
+
+            let path = archive_path(&entry.path()?)?;
+            if path.is_root() {
+                continue;
+            }
+
+            match entry_type {
+                EntryType::Directory => manager.create_dir_all(&path)?,
+                EntryType::Regular => {
+                    if let Some(parent) = path.parent() {
+                        manager.create_dir_all(&parent)?;
+                    }
+                    let mut file =
+                        manager.open(&path, OpenOptions::new().write(true).create(true))?;
+                    let bytes_copied = io::copy(&mut entry, &mut file)?;
+                    // Discard any longer contents from an earlier entry.
+                    file.set_len(bytes_copied)?;
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported archive entry type: {other:?}"),
+                    ))
+                }
+            }
+        }
+
+        sync_tree(&manager, &PathId::root())?;
+        Ok(manager)
+    }
+}
+
+/// Converts a path within an archive to an absolute path.
+fn archive_path(path: &Path) -> io::Result<PathId> {
+    let mut absolute = PathId::root().to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => absolute.push(name),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid archive path: {}", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(PathId::from(absolute))
+}
@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::rng::Rng;
+use crate::PathId;
+
+/// A set of faults to inject into a
+/// [`MemoryFileManager`](super::MemoryFileManager).
+///
+/// Each fault is evaluated against every operation the manager and its files
+/// perform. The first fault that triggers causes the operation to fail with the
+/// fault's error kind, before the operation has any effect.
+///
+/// Counting and random faults are deterministic: as long as the same
+/// operations are performed in the same order, the same operations will fail.
+/// Random faults are driven by the schedule's seed.
+#[derive(Debug, Clone)]
+pub struct FaultSchedule {
+    faults: Vec<Fault>,
+    rng: Rng,
+}
+
+impl FaultSchedule {
+    /// Returns an empty schedule. `seed` drives any faults created with
+    /// [`Fault::random()`].
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            faults: Vec::new(),
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Adds `fault` to this schedule.
+    #[must_use]
+    pub fn with(mut self, fault: Fault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// Adds `fault` to this schedule.
+    pub fn push(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    pub(super) fn check(&mut self, operation: OperationKind, path: &PathId) -> io::Result<()> {
+        for fault in &mut self.faults {
+            if fault.operation != operation
+                || !fault.paths.as_ref().is_none_or(|paths| paths.0(path))
+            {
+                continue;
+            }
+
+            fault.calls += 1;
+            let triggered = match fault.trigger {
+                Trigger::Nth(n) => fault.calls == n,
+                Trigger::Every => true,
+                Trigger::Random(probability) => self.rng.next_f64() < probability,
+            };
+            if triggered {
+                return Err(io::Error::new(
+                    fault.kind,
+                    format!(
+                        "injected fault: {operation:?} #{} of {}",
+                        fault.calls,
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A fault that can be injected using a [`FaultSchedule`].
+#[derive(Debug, Clone)]
+pub struct Fault {
+    operation: OperationKind,
+    trigger: Trigger,
+    kind: io::ErrorKind,
+    paths: Option<PathFilter>,
+    calls: u64,
+}
+
+impl Fault {
+    /// Fails the `n`th call of `operation` with an error of `kind`. The first
+    /// call is 1.
+    #[must_use]
+    pub const fn nth(operation: OperationKind, n: u64, kind: io::ErrorKind) -> Self {
+        Self::new(operation, Trigger::Nth(n), kind)
+    }
+
+    /// Fails every call of `operation` with an error of `kind`.
+    #[must_use]
+    pub const fn every(operation: OperationKind, kind: io::ErrorKind) -> Self {
+        Self::new(operation, Trigger::Every, kind)
+    }
+
+    /// Fails each call of `operation` with an error of `kind` with the given
+    /// probability, which ranges from `0.0` to `1.0`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is outside of `0.0..=1.0`, or is NaN.
+    #[must_use]
+    pub const fn random(operation: OperationKind, probability: f64, kind: io::ErrorKind) -> Self {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "probability must be between 0.0 and 1.0"
+        );
+        Self::new(operation, Trigger::Random(probability), kind)
+    }
+
+    const fn new(operation: OperationKind, trigger: Trigger, kind: io::ErrorKind) -> Self {
+        Self {
+            operation,
+            trigger,
+            kind,
+            paths: None,
+            calls: 0,
+        }
+    }
+
+    /// Restricts this fault to operations on paths for which `matches`
+    /// returns true. Calls on other paths aren't counted.
+    ///
+    /// File operations are matched against the path the file was opened with,
+    /// and renames are matched against the original path.
+    #[must_use]
+    pub fn matching<F>(mut self, matches: F) -> Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.paths = Some(PathFilter(Arc::new(matches)));
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Trigger {
+    Nth(u64),
+    Every,
+    Random(f64),
+}
+
+#[derive(Clone)]
+struct PathFilter(Arc<dyn Fn(&Path) -> bool + Send + Sync>);
+
+impl Debug for PathFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PathFilter").finish_non_exhaustive()
+    }
+}
+
+/// Randomly corrupts the stored contents of
+/// [`MemoryFile`](super::MemoryFile)s as they are read, simulating silent
+/// data corruption by the storage media.
+///
+/// Each read has a chance of flipping a single bit of the stored bytes it is
+/// about to return. The corruption is permanent: later reads of the same bytes
+/// return the corrupted data, and it survives a
+/// [`crash()`](super::MemoryFileManager::crash) if the bytes were synced.
+#[derive(Debug, Clone)]
+pub struct BitRot {
+    probability: f64,
+    paths: Option<PathFilter>,
+    rng: Rng,
+}
+
+impl BitRot {
+    /// Corrupts each read with the given probability, which ranges from `0.0`
+    /// to `1.0`. `seed` determines which reads are corrupted and which bits
+    /// are flipped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is outside of `0.0..=1.0`, or is NaN.
+    #[must_use]
+    pub const fn new(seed: u64, probability: f64) -> Self {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "probability must be between 0.0 and 1.0"
+        );
+        Self {
+            probability,
+            paths: None,
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Restricts corruption to files whose path, as they were opened with,
+    /// `matches`.
+    #[must_use]
+    pub fn matching<F>(mut self, matches: F) -> Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.paths = Some(PathFilter(Arc::new(matches)));
+        self
+    }
+
+    /// Returns the offset within a read of `length` bytes and the mask of the
+    /// bit to flip, if this read should be corrupted.
+    pub(super) fn sample(&mut self, path: &PathId, length: usize) -> Option<(usize, u8)> {
+        if length == 0
+            || !self.paths.as_ref().is_none_or(|paths| paths.0(path))
+            || self.rng.next_f64() >= self.probability
+        {
+            return None;
+        }
+
+        let bits = self.rng.next_u64();
+        let offset = usize::try_from(bits % length as u64).expect("less than length");
+        Some((offset, 1 << (bits >> 61)))
+    }
+}
+
+/// The operations of a [`MemoryFileManager`](super::MemoryFileManager) and
+/// its files.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum OperationKind {
+    /// [`FileManager::open()`](crate::FileManager::open)
+    Open,
+    /// [`FileManager::create_dir_all()`](crate::FileManager::create_dir_all)
+    CreateDirectory,
+    /// [`FileManager::remove_dir_all()`](crate::FileManager::remove_dir_all)
+    RemoveDirectory,
+    /// [`FileManager::remove_file()`](crate::FileManager::remove_file)
+    RemoveFile,
+    /// [`FileManager::rename()`](crate::FileManager::rename)
+    Rename,
+    /// [`FileManager::list()`](crate::FileManager::list)
+    List,
+    /// [`Read::read()`](std::io::Read::read)
+    Read,
+    /// [`Write::write()`](std::io::Write::write)
+    Write,
+    /// [`File::set_len()`](crate::File::set_len)
+    SetLength,
+    /// [`File::sync_data()`](crate::File::sync_data)
+    SyncData,
+    /// [`File::sync_all()`](crate::File::sync_all)
+    SyncAll,
+}
+
+/// Makes reads and writes of a [`MemoryFile`](super::MemoryFile) transfer
+/// fewer bytes than requested, or fail with [`io::ErrorKind::Interrupted`].
+///
+/// Both are legitimate results of [`Read::read()`](std::io::Read::read) and
+/// [`Write::write()`](std::io::Write::write). This can be used to verify that
+/// code uses `read_exact()`/`write_all()` or otherwise retries correctly.
+///
+/// By default, every call transfers as many bytes as it would normally, and no
+/// calls are interrupted. Calls with an empty buffer are never affected.
+#[derive(Debug, Clone)]
+pub struct PartialIo {
+    length: PartialLength,
+    interrupt: Option<Interruption>,
+    calls: u64,
+    rng: Rng,
+}
+
+impl PartialIo {
+    /// Returns a new configuration that uses `seed` for any random decisions.
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            length: PartialLength::Full,
+            interrupt: None,
+            calls: 0,
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Limits each call to transferring at most `bytes` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is 0.
+    #[must_use]
+    pub const fn at_most(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "at least one byte must be transferred");
+        self.length = PartialLength::AtMost(bytes);
+        self
+    }
+
+    /// Makes each call transfer a random number of bytes between 1 and the
+    /// number requested.
+    #[must_use]
+    pub const fn random_lengths(mut self) -> Self {
+        self.length = PartialLength::Random;
+        self
+    }
+
+    /// Interrupts every `n`th call.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    #[must_use]
+    pub const fn interrupt_every(mut self, n: u64) -> Self {
+        assert!(n > 0, "n must be non-zero");
+        self.interrupt = Some(Interruption::Nth(n));
+        self
+    }
+
+    /// Interrupts each call with the given probability, which ranges from
+    /// `0.0` to `1.0`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is outside of `0.0..=1.0`, or is NaN.
+    #[must_use]
+    pub const fn interrupt_randomly(mut self, probability: f64) -> Self {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "probability must be between 0.0 and 1.0"
+        );
+        self.interrupt = Some(Interruption::Random(probability));
+        self
+    }
+
+    /// Returns how many of the `requested` bytes the next call should
+    /// transfer.
+    pub(super) fn limit(&mut self, requested: usize) -> io::Result<usize> {
+        if requested == 0 {
+            return Ok(0);
+        }
+
+        self.calls += 1;
+        let interrupted = match self.interrupt {
+            Some(Interruption::Nth(n)) => self.calls % n == 0,
+            Some(Interruption::Random(probability)) => self.rng.next_f64() < probability,
+            None => false,
+        };
+        if interrupted {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "injected interruption",
+            ));
+        }
+
+        Ok(match self.length {
+            PartialLength::Full => requested,
+            PartialLength::AtMost(bytes) => requested.min(bytes),
+            PartialLength::Random => {
+                // Truncating is fine, as the result is less than requested.
+                #[allow(clippy::cast_possible_truncation)]
+                let bytes = (self.rng.next_u64() % requested as u64) as usize;
+                bytes + 1
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PartialLength {
+    Full,
+    AtMost(usize),
+    Random,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Interruption {
+    /// Every nth call is interrupted. Never zero.
+    Nth(u64),
+    Random(f64),
+}
+
+/// Per-operation latencies to simulate in a
+/// [`MemoryFileManager`](super::MemoryFileManager).
+///
+/// The calling thread sleeps for the simulated latency without holding any
+/// locks, so that operations from multiple threads can overlap like they
+/// would on a real disk.
+#[derive(Debug, Clone)]
+pub struct LatencyProfile {
+    latencies: HashMap<OperationKind, Latency>,
+    rng: Rng,
+}
+
+impl LatencyProfile {
+    /// Returns a profile without any latency. `seed` drives any latencies
+    /// that follow a distribution.
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self {
+            latencies: HashMap::new(),
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Sets the latency of `operation`.
+    #[must_use]
+    pub fn with(mut self, operation: OperationKind, latency: Latency) -> Self {
+        self.latencies.insert(operation, latency);
+        self
+    }
+
+    /// Sets the latency of `operation`.
+    pub fn set(&mut self, operation: OperationKind, latency: Latency) {
+        self.latencies.insert(operation, latency);
+    }
+
+    pub(super) fn sample(&mut self, operation: OperationKind) -> Duration {
+        match self.latencies.get(&operation) {
+            Some(Latency::Fixed(latency)) => *latency,
+            Some(Latency::Uniform { minimum, maximum }) => {
+                let range = maximum.saturating_sub(*minimum);
+                *minimum + range.mul_f64(self.rng.next_f64())
+            }
+            Some(Latency::Exponential { mean }) => {
+                // Inverse transform sampling. 1 - x is in the range 0.0..1.0,
+                // avoiding ln(0).
+                mean.mul_f64(-(1.0 - self.rng.next_f64()).ln())
+            }
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// The simulated latency of an operation.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Latency {
+    /// Every call takes the same amount of time.
+    Fixed(Duration),
+    /// Each call takes a random amount of time between `minimum` and
+    /// `maximum`.
+    Uniform {
+        /// The shortest latency.
+        minimum: Duration,
+        /// The longest latency.
+        maximum: Duration,
+    },
+    /// Each call takes a random amount of time that follows an exponential
+    /// distribution. Most calls are fast, but there is a long tail of slow
+    /// calls.
+    Exponential {
+        /// The average latency.
+        mean: Duration,
+    },
+}
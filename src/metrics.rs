@@ -0,0 +1,611 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::fsync::FSyncManager;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// A [`FileManager`] that measures every operation performed using another
+/// manager.
+///
+/// The number of calls, failed calls, bytes transferred and a latency
+/// histogram are recorded for each [`Operation`], both in total and for each
+/// path. This allows measuring write amplification or the number of syncs a
+/// workload performs without modifying its code.
+///
+/// Clones of this manager and the files it opens all record into the same
+/// metrics. Metrics are recorded using atomic counters, and each open file
+/// keeps the counters of its path, so measuring file operations doesn't
+/// require any locking.
+#[derive(Clone, Debug)]
+pub struct MetricsFileManager<M>
+where
+    M: FileManager,
+{
+    inner: M,
+    recorder: Arc<Recorder>,
+    fsyncs: FSyncManager<Self>,
+}
+
+impl<M> MetricsFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that measures the operations performed on `inner`.
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            recorder: Arc::default(),
+            fsyncs: FSyncManager::default(),
+        }
+    }
+
+    /// Returns the wrapped manager.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Returns a copy of the metrics recorded so far.
+    #[must_use]
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.recorder.snapshot(false)
+    }
+
+    /// Clears all recorded metrics, returning the metrics that were recorded
+    /// before clearing them.
+    pub fn reset(&self) -> MetricsSnapshot {
+        self.recorder.snapshot(true)
+    }
+}
+
+impl<M> FileManager for MetricsFileManager<M>
+where
+    M: FileManager,
+{
+    type File = MetricsFile<M>;
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        let counters = self.recorder.counters(path);
+        let file = self.recorder.measure(&[&counters], Operation::Open, || {
+            self.inner.open(path, options)
+        })?;
+        Ok(MetricsFile {
+            file,
+            recorder: self.recorder.clone(),
+            counters,
+        })
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.recorder
+            .measure_path(path, Operation::Exists, || Ok(self.inner.exists(path)))
+            .unwrap_or(false)
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.recorder
+            .measure_path(path, Operation::CreateDirectory, || {
+                self.inner.create_dir_all(path)
+            })
+    }
+
+    fn remove_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.recorder
+            .measure_path(path, Operation::RemoveDirectory, || {
+                self.inner.remove_dir_all(path)
+            })
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        self.recorder
+            .measure_path(path, Operation::RemoveFile, || self.inner.remove_file(path))
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        let from_counters = self.recorder.counters(from);
+        let to_counters = self.recorder.counters(&to);
+        self.recorder
+            .measure(&[&from_counters, &to_counters], Operation::Rename, || {
+                self.inner.rename(from, to)
+            })
+    }
+
+    fn new_fsync_batch(&self) -> io::Result<crate::FSyncBatch<Self>> {
+        Ok(self.fsyncs.new_batch()?)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.fsyncs.shutdown()?;
+        Ok(())
+    }
+
+    fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        self.recorder
+            .measure_path(path, Operation::List, || self.inner.list(path))
+    }
+}
+
+/// An operation measured by a [`MetricsFileManager`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Operation {
+    /// [`FileManager::open()`]
+    Open,
+    /// [`FileManager::exists()`]
+    Exists,
+    /// [`FileManager::create_dir_all()`]
+    CreateDirectory,
+    /// [`FileManager::remove_dir_all()`]
+    RemoveDirectory,
+    /// [`FileManager::remove_file()`]
+    RemoveFile,
+    /// [`FileManager::rename()`], which is recorded for both the original and
+    /// the new path.
+    Rename,
+    /// [`FileManager::list()`]
+    List,
+    /// [`Read::read()`]
+    Read,
+    /// [`Write::write()`]
+    Write,
+    /// [`Write::flush()`]
+    Flush,
+    /// [`Seek::seek()`]
+    Seek,
+    /// [`File::len()`]
+    Length,
+    /// [`File::set_len()`]
+    SetLength,
+    /// [`File::sync_data()`]
+    SyncData,
+    /// [`File::sync_all()`]
+    SyncAll,
+}
+
+impl Operation {
+    /// Every operation, in the order they are declared.
+    const ALL: [Self; 15] = [
+        Self::Open,
+        Self::Exists,
+        Self::CreateDirectory,
+        Self::RemoveDirectory,
+        Self::RemoveFile,
+        Self::Rename,
+        Self::List,
+        Self::Read,
+        Self::Write,
+        Self::Flush,
+        Self::Seek,
+        Self::Length,
+        Self::SetLength,
+        Self::SyncData,
+        Self::SyncAll,
+    ];
+}
+
+/// The metrics recorded by a [`MetricsFileManager`].
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    /// The metrics of each operation, across all paths.
+    pub operations: HashMap<Operation, OperationMetrics>,
+    /// The metrics of each operation, for each path it was performed on.
+    pub paths: HashMap<PathId, HashMap<Operation, OperationMetrics>>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the number of times `operation` was performed.
+    #[must_use]
+    pub fn count(&self, operation: Operation) -> u64 {
+        self.operations
+            .get(&operation)
+            .map_or(0, |metrics| metrics.count)
+    }
+
+    /// Returns the number of bytes transferred by `operation`.
+    #[must_use]
+    pub fn bytes(&self, operation: Operation) -> u64 {
+        self.operations
+            .get(&operation)
+            .map_or(0, |metrics| metrics.bytes)
+    }
+
+    /// Returns the metrics of `operation` performed on `path`.
+    #[must_use]
+    pub fn path(&self, path: &PathId, operation: Operation) -> Option<&OperationMetrics> {
+        self.paths
+            .get(path)
+            .and_then(|operations| operations.get(&operation))
+    }
+}
+
+/// The metrics of a single [`Operation`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OperationMetrics {
+    /// The number of times the operation was performed.
+    pub count: u64,
+    /// The number of times the operation failed.
+    pub errors: u64,
+    /// The number of bytes read or written. This is only recorded for
+    /// [`Operation::Read`] and [`Operation::Write`].
+    pub bytes: u64,
+    /// The distribution of the operation's latency.
+    pub latency: LatencyHistogram,
+}
+
+const BUCKETS: usize = 48;
+
+/// A histogram of latencies, using buckets whose bounds are powers of two
+/// nanoseconds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    /// The bucket at index `i` counts latencies below `2^i` nanoseconds that
+    /// aren't counted by a lower bucket. The last bucket counts all longer
+    /// latencies.
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    maximum: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            total: Duration::ZERO,
+            maximum: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Returns the number of recorded latencies.
+    #[must_use]
+    pub const fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of all recorded latencies.
+    #[must_use]
+    pub const fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Returns the longest recorded latency.
+    #[must_use]
+    pub const fn maximum(&self) -> Duration {
+        self.maximum
+    }
+
+    /// Returns the average recorded latency.
+    #[must_use]
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_nanos(
+                u64::try_from(self.total.as_nanos() / u128::from(self.count)).unwrap_or(u64::MAX),
+            )
+        }
+    }
+
+    /// Returns an upper bound of the latency that `percentile` percent of the
+    /// recorded latencies don't exceed. The result is rounded up to the next
+    /// power of two nanoseconds, but never exceeds [`Self::maximum()`].
+    #[must_use]
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let target = ((percentile / 100.).clamp(0., 1.) * self.count as f64).ceil() as u64;
+        let mut counted = 0;
+        for (upper_bound, count) in self.buckets() {
+            counted += count;
+            if counted >= target.max(1) {
+                return upper_bound.min(self.maximum);
+            }
+        }
+        self.maximum
+    }
+
+    /// Returns the exclusive upper bound and the count of each non-empty
+    /// bucket, from the shortest latencies to the longest.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| {
+                let upper_bound = if bucket == BUCKETS - 1 {
+                    Duration::MAX
+                } else {
+                    Duration::from_nanos(1 << bucket)
+                };
+                (upper_bound, *count)
+            })
+    }
+}
+
+/// Returns the value of `counter`, setting it to zero if `reset` is true.
+fn read_counter(counter: &AtomicU64, reset: bool) -> u64 {
+    if reset {
+        counter.swap(0, Ordering::Relaxed)
+    } else {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// The counters behind a [`LatencyHistogram`].
+#[derive(Debug)]
+struct LatencyCounters {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    maximum_nanos: AtomicU64,
+}
+
+impl Default for LatencyCounters {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            total_nanos: AtomicU64::new(0),
+            maximum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyCounters {
+    fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = ((u64::BITS - nanos.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.maximum_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn histogram(&self, reset: bool) -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: std::array::from_fn(|bucket| read_counter(&self.buckets[bucket], reset)),
+            count: read_counter(&self.count, reset),
+            total: Duration::from_nanos(read_counter(&self.total_nanos, reset)),
+            maximum: Duration::from_nanos(read_counter(&self.maximum_nanos, reset)),
+        }
+    }
+}
+
+/// The counters behind an [`OperationMetrics`].
+#[derive(Debug, Default)]
+struct OperationCounters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    bytes: AtomicU64,
+    latency: LatencyCounters,
+}
+
+/// The metrics of each [`Operation`], recorded without locking.
+#[derive(Debug, Default)]
+struct Counters([OperationCounters; Operation::ALL.len()]);
+
+impl Counters {
+    fn record(&self, operation: Operation, failed: bool, bytes: u64, latency: Duration) {
+        let counters = &self.0[operation as usize];
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters
+            .errors
+            .fetch_add(u64::from(failed), Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        counters.latency.record(latency);
+    }
+
+    /// Returns the metrics of each operation that has been performed.
+    fn snapshot(&self, reset: bool) -> HashMap<Operation, OperationMetrics> {
+        Operation::ALL
+            .into_iter()
+            .zip(&self.0)
+            .filter_map(|(operation, counters)| {
+                let metrics = OperationMetrics {
+                    count: read_counter(&counters.count, reset),
+                    errors: read_counter(&counters.errors, reset),
+                    bytes: read_counter(&counters.bytes, reset),
+                    latency: counters.latency.histogram(reset),
+                };
+                (metrics.count > 0).then_some((operation, metrics))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Recorder {
+    /// The metrics of each operation, across all paths.
+    total: Counters,
+    /// The metrics of each path. Open files keep the counters of their path,
+    /// so this is only accessed when opening files and by manager operations.
+    paths: RwLock<HashMap<PathId, Arc<Counters>>>,
+}
+
+impl Recorder {
+    /// Returns the counters of `path`.
+    fn counters(&self, path: &PathId) -> Arc<Counters> {
+        let paths = self.paths.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counters) = paths.get(path) {
+            return counters.clone();
+        }
+        drop(paths);
+
+        self.paths
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(path.clone())
+            .or_default()
+            .clone()
+    }
+
+    fn snapshot(&self, reset: bool) -> MetricsSnapshot {
+        let paths = self.paths.read().unwrap_or_else(PoisonError::into_inner);
+        MetricsSnapshot {
+            operations: self.total.snapshot(reset),
+            paths: paths
+                .iter()
+                .map(|(path, counters)| (path.clone(), counters.snapshot(reset)))
+                .filter(|(_, operations)| !operations.is_empty())
+                .collect(),
+        }
+    }
+
+    fn measure_path<T>(
+        &self,
+        path: &PathId,
+        operation: Operation,
+        perform: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.measure(&[&self.counters(path)], operation, perform)
+    }
+
+    fn measure<T>(
+        &self,
+        paths: &[&Counters],
+        operation: Operation,
+        perform: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.measure_transfer(paths, operation, perform, |_| 0)
+    }
+
+    /// Performs `operation`, and records it in the totals and in the
+    /// counters of each of `paths`.
+    fn measure_transfer<T>(
+        &self,
+        paths: &[&Counters],
+        operation: Operation,
+        perform: impl FnOnce() -> io::Result<T>,
+        transferred: impl FnOnce(&T) -> usize,
+    ) -> io::Result<T> {
+        let start = Instant::now();
+        let result = perform();
+        let latency = start.elapsed();
+        let bytes = result.as_ref().map_or(0, transferred) as u64;
+
+        for counters in std::iter::once(&self.total).chain(paths.iter().copied()) {
+            counters.record(operation, result.is_err(), bytes, latency);
+        }
+
+        result
+    }
+}
+
+/// A file opened by a [`MetricsFileManager`].
+#[derive(Debug)]
+pub struct MetricsFile<M>
+where
+    M: FileManager,
+{
+    file: M::File,
+    recorder: Arc<Recorder>,
+    counters: Arc<Counters>,
+}
+
+impl<M> File for MetricsFile<M>
+where
+    M: FileManager,
+{
+    type Manager = MetricsFileManager<M>;
+
+    fn path(&self) -> &PathId {
+        self.file.path()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.recorder
+            .measure(&[&self.counters], Operation::SyncAll, || {
+                self.file.sync_all()
+            })
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.recorder
+            .measure(&[&self.counters], Operation::SyncData, || {
+                self.file.sync_data()
+            })
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.recorder
+            .measure(&[&self.counters], Operation::Length, || self.file.len())
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        self.recorder
+            .measure(&[&self.counters], Operation::SetLength, || {
+                self.file.set_len(new_length)
+            })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            recorder: self.recorder.clone(),
+            counters: self.counters.clone(),
+        })
+    }
+}
+
+impl<M> Read for MetricsFile<M>
+where
+    M: FileManager,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Self {
+            file,
+            recorder,
+            counters,
+        } = self;
+        recorder.measure_transfer(
+            &[counters],
+            Operation::Read,
+            || file.read(buf),
+            |bytes| *bytes,
+        )
+    }
+}
+
+impl<M> Write for MetricsFile<M>
+where
+    M: FileManager,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Self {
+            file,
+            recorder,
+            counters,
+        } = self;
+        recorder.measure_transfer(
+            &[counters],
+            Operation::Write,
+            || file.write(buf),
+            |bytes| *bytes,
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Self {
+            file,
+            recorder,
+            counters,
+        } = self;
+        recorder.measure(&[counters], Operation::Flush, || file.flush())
+    }
+}
+
+impl<M> Seek for MetricsFile<M>
+where
+    M: FileManager,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let Self {
+            file,
+            recorder,
+            counters,
+        } = self;
+        recorder.measure(&[counters], Operation::Seek, || file.seek(pos))
+    }
+}
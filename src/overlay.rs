@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::fsync::FSyncManager;
+use crate::tree::copy_tree;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// A [`FileManager`] that combines a read-only lower layer with a writable
+/// upper layer.
+///
+/// Files are read from the lower layer until they are opened for writing. At
+/// that point, the file is copied into the upper layer, and all further access
+/// uses the copy. New files and directories are only created in the upper
+/// layer. Removing a file or directory that exists in the lower layer records
+/// a whiteout, which hides the path and everything beneath it in the lower
+/// layer. The lower layer is never modified.
+///
+/// Whiteouts are only tracked in memory. Files opened from the lower layer
+/// keep reading the lower layer's contents, even after the file has been
+/// copied up by another handle.
+#[derive(Clone, Debug)]
+pub struct OverlayFileManager<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    lower: Lower,
+    upper: Upper,
+    whiteouts: Arc<Mutex<HashSet<PathId>>>,
+    fsyncs: FSyncManager<Self>,
+}
+
+impl<Lower, Upper> OverlayFileManager<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    /// Returns a manager that reads from `lower` and writes to `upper`.
+    pub fn new(lower: Lower, upper: Upper) -> Self {
+        Self {
+            lower,
+            upper,
+            whiteouts: Arc::default(),
+            fsyncs: FSyncManager::default(),
+        }
+    }
+
+    /// Returns the read-only lower layer.
+    pub fn lower(&self) -> &Lower {
+        &self.lower
+    }
+
+    /// Returns the writable upper layer.
+    pub fn upper(&self) -> &Upper {
+        &self.upper
+    }
+
+    /// Returns true if `path` exists in the lower layer and hasn't been hidden
+    /// by a whiteout.
+    fn in_lower(&self, path: &PathId) -> bool {
+        let whiteouts = self
+            .whiteouts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        path.ancestors()
+            .all(|ancestor| !whiteouts.contains(&PathId::from(ancestor)))
+            && self.lower.exists(path)
+    }
+
+    /// Hides `path` in the lower layer, if it exists there.
+    fn white_out(&self, path: &PathId) {
+        if self.lower.exists(path) {
+            self.whiteouts
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(path.clone());
+        }
+    }
+
+    fn is_directory(&self, path: &PathId) -> bool {
+        if self.upper.exists(path) {
+            self.upper.list(path).is_ok()
+        } else {
+            self.in_lower(path) && self.lower.list(path).is_ok()
+        }
+    }
+
+    /// Ensures the parent directory of `path` exists in the overlay, and
+    /// creates it in the upper layer.
+    fn prepare_parent(&self, path: &PathId) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if self.is_directory(&parent) => self.upper.create_dir_all(&parent),
+            Some(_) => Err(io::Error::from(io::ErrorKind::NotFound)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<Lower, Upper> FileManager for OverlayFileManager<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    type File = OverlayFile<Lower, Upper>;
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        if self.upper.exists(path) {
+            self.upper.open(path, options).map(OverlayFile::Upper)
+        } else if self.in_lower(path) {
+            if options.write {
+                // Copy the file up before it is modified. Copying through the
+                // overlay ensures only what the overlay shows is copied.
+                copy_tree(self, path, &self.upper, path)?;
+                self.upper.open(path, options).map(OverlayFile::Upper)
+            } else {
+                self.lower.open(path, options).map(OverlayFile::Lower)
+            }
+        } else if options.create {
+            self.prepare_parent(path)?;
+            self.upper.open(path, options).map(OverlayFile::Upper)
+        } else {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.upper.exists(path) || self.in_lower(path)
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        // Directories can't be created beneath a file of either layer.
+        if let Some(file) = path
+            .ancestors()
+            .map(PathId::from)
+            .find(|ancestor| self.exists(ancestor))
+        {
+            if !self.is_directory(&file) {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            }
+        }
+        self.upper.create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &PathId) -> io::Result<()> {
+        if !self.exists(path) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if self.upper.exists(path) {
+            self.upper.remove_dir_all(path)?;
+        }
+        self.white_out(path);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        if !self.exists(path) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if self.upper.exists(path) {
+            self.upper.remove_file(path)?;
+        }
+        self.white_out(path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        if !self.exists(from) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if from != &to && self.exists(&to) {
+            // Like a real file system, only a file can replace a file, and
+            // only a directory can replace an empty directory.
+            match (self.is_directory(from), self.is_directory(&to)) {
+                (false, true) => return Err(io::Error::from(io::ErrorKind::IsADirectory)),
+                (true, false) => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+                (true, true) if !self.list(&to)?.is_empty() => {
+                    return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty))
+                }
+                _ => {}
+            }
+        }
+        self.prepare_parent(&to)?;
+
+        if self.in_lower(from) {
+            // The lower layer can't be modified, so the combined contents are
+            // copied up to the new location instead.
+            copy_tree(self, from, &self.upper, &to)?;
+            if self.upper.exists(from) {
+                if self.upper.list(from).is_ok() {
+                    self.upper.remove_dir_all(from)?;
+                } else {
+                    self.upper.remove_file(from)?;
+                }
+            }
+            self.white_out(from);
+        } else {
+            self.upper.rename(from, to.clone())?;
+        }
+        // Anything the lower layer has at the destination was replaced.
+        self.white_out(&to);
+        Ok(())
+    }
+
+    fn new_fsync_batch(&self) -> io::Result<crate::FSyncBatch<Self>> {
+        Ok(self.fsyncs.new_batch()?)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.fsyncs.shutdown()?;
+        Ok(())
+    }
+
+    fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        let upper = if self.upper.exists(path) {
+            Some(self.upper.list(path)?)
+        } else {
+            None
+        };
+        let lower = if self.in_lower(path) {
+            Some(self.lower.list(path)?)
+        } else {
+            None
+        };
+        if upper.is_none() && lower.is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        let mut entries = upper.unwrap_or_default();
+        let mut seen = entries.iter().cloned().collect::<HashSet<_>>();
+        for entry in lower.into_iter().flatten() {
+            if self.in_lower(&entry) && seen.insert(entry.clone()) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// A file opened by an [`OverlayFileManager`].
+#[derive(Debug)]
+pub enum OverlayFile<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    /// A file that was opened for reading from the lower layer. Writing to it
+    /// fails with [`io::ErrorKind::PermissionDenied`].
+    Lower(Lower::File),
+    /// A file in the upper layer.
+    Upper(Upper::File),
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "file was opened from the read-only lower layer",
+    )
+}
+
+impl<Lower, Upper> File for OverlayFile<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    type Manager = OverlayFileManager<Lower, Upper>;
+
+    fn path(&self) -> &PathId {
+        match self {
+            OverlayFile::Lower(file) => file.path(),
+            OverlayFile::Upper(file) => file.path(),
+        }
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        match self {
+            OverlayFile::Lower(file) => file.sync_all(),
+            OverlayFile::Upper(file) => file.sync_all(),
+        }
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        match self {
+            OverlayFile::Lower(file) => file.sync_data(),
+            OverlayFile::Upper(file) => file.sync_data(),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            OverlayFile::Lower(file) => file.len(),
+            OverlayFile::Upper(file) => file.len(),
+        }
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        match self {
+            OverlayFile::Lower(_) => Err(read_only()),
+            OverlayFile::Upper(file) => file.set_len(new_length),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            OverlayFile::Lower(file) => file.try_clone().map(OverlayFile::Lower),
+            OverlayFile::Upper(file) => file.try_clone().map(OverlayFile::Upper),
+        }
+    }
+}
+
+impl<Lower, Upper> Read for OverlayFile<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            OverlayFile::Lower(file) => file.read(buf),
+            OverlayFile::Upper(file) => file.read(buf),
+        }
+    }
+}
+
+impl<Lower, Upper> Write for OverlayFile<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OverlayFile::Lower(_) => Err(read_only()),
+            OverlayFile::Upper(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OverlayFile::Lower(_) => Ok(()),
+            OverlayFile::Upper(file) => file.flush(),
+        }
+    }
+}
+
+impl<Lower, Upper> Seek for OverlayFile<Lower, Upper>
+where
+    Lower: FileManager,
+    Upper: FileManager,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            OverlayFile::Lower(file) => file.seek(pos),
+            OverlayFile::Upper(file) => file.seek(pos),
+        }
+    }
+}
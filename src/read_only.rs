@@ -0,0 +1,152 @@
+use std::io::{self, Read, Seek, Write};
+
+use crate::fsync::FSyncManager;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// A [`FileManager`] that prevents all modifications to another manager.
+///
+/// Files can only be opened for reading. Creating, removing and renaming
+/// files and directories, and writing to or resizing files, fail with
+/// [`io::ErrorKind::PermissionDenied`]. The wrapped manager isn't accessible
+/// through this type, so code given a `ReadOnlyFileManager` can't modify the
+/// files it examines.
+#[derive(Clone, Debug)]
+pub struct ReadOnlyFileManager<M>
+where
+    M: FileManager,
+{
+    inner: M,
+    fsyncs: FSyncManager<Self>,
+}
+
+impl<M> ReadOnlyFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that provides read-only access to `inner`.
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            fsyncs: FSyncManager::default(),
+        }
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "file manager is read-only")
+}
+
+impl<M> FileManager for ReadOnlyFileManager<M>
+where
+    M: FileManager,
+{
+    type File = ReadOnlyFile<M>;
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        if options.write || options.create {
+            return Err(read_only());
+        }
+        self.inner.open(path, options).map(ReadOnlyFile)
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn create_dir_all(&self, _path: &PathId) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn remove_dir_all(&self, _path: &PathId) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn remove_file(&self, _path: &PathId) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn rename(&self, _from: &PathId, _to: PathId) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn new_fsync_batch(&self) -> io::Result<crate::FSyncBatch<Self>> {
+        Ok(self.fsyncs.new_batch()?)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.fsyncs.shutdown()?;
+        Ok(())
+    }
+
+    fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        self.inner.list(path)
+    }
+}
+
+/// A file opened by a [`ReadOnlyFileManager`].
+#[derive(Debug)]
+pub struct ReadOnlyFile<M>(M::File)
+where
+    M: FileManager;
+
+impl<M> File for ReadOnlyFile<M>
+where
+    M: FileManager,
+{
+    type Manager = ReadOnlyFileManager<M>;
+
+    fn path(&self) -> &PathId {
+        self.0.path()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.0.len()
+    }
+
+    fn set_len(&self, _new_length: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        self.0.try_clone().map(Self)
+    }
+}
+
+impl<M> Read for ReadOnlyFile<M>
+where
+    M: FileManager,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<M> Write for ReadOnlyFile<M>
+where
+    M: FileManager,
+{
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<M> Seek for ReadOnlyFile<M>
+where
+    M: FileManager,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
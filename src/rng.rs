@@ -0,0 +1,31 @@
+/// A small, seedable pseudorandom number generator (SplitMix64).
+///
+/// Simulations need to be reproducible from a seed forever, so this is
+/// implemented here rather than relying on an external crate whose algorithms
+/// may change between versions.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub const fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut value = self.0;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^ (value >> 31)
+    }
+
+    pub fn next_bool(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    /// Returns a value in the range `0.0..1.0`.
+    pub fn next_f64(&mut self) -> f64 {
+        // Use the upper 53 bits, which is the precision of an f64.
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
@@ -0,0 +1,244 @@
+use std::io::{self, Read, Seek, Write};
+use std::path::Component;
+
+use crate::fsync::FSyncManager;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// A [`FileManager`] that exposes a directory of another manager as its root.
+///
+/// Every path passed to this manager is resolved relative to the base
+/// directory, so `/db/wal` refers to `<base>/db/wal`. This allows the same
+/// paths to be used with a [`MemoryFileManager`](crate::memory::MemoryFileManager)
+/// and with a temporary directory on disk. Paths returned by this manager, such
+/// as those from [`FileManager::list()`], are relative to the base directory
+/// as well.
+///
+/// Paths that would escape the base directory using `..` are rejected with
+/// [`io::ErrorKind::InvalidInput`]. Paths are resolved without accessing the
+/// inner manager, so symbolic links inside of the base directory can still
+/// point outside of it.
+#[derive(Clone, Debug)]
+pub struct ScopedFileManager<M>
+where
+    M: FileManager,
+{
+    inner: M,
+    base: PathId,
+    fsyncs: FSyncManager<Self>,
+}
+
+impl<M> ScopedFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager whose root is `base` in `inner`. The base directory
+    /// must already exist.
+    pub fn new(inner: M, base: PathId) -> Self {
+        Self {
+            inner,
+            base,
+            fsyncs: FSyncManager::default(),
+        }
+    }
+
+    /// Returns the wrapped manager.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Returns the directory of the wrapped manager that is this manager's
+    /// root.
+    pub fn base(&self) -> &PathId {
+        &self.base
+    }
+
+    fn inner_path(&self, path: &PathId) -> io::Result<PathId> {
+        let mut inner = self.base.to_path_buf();
+        let mut depth = 0_usize;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => {
+                    inner.push(name);
+                    depth += 1;
+                }
+                Component::ParentDir if depth > 0 => {
+                    inner.pop();
+                    depth -= 1;
+                }
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} escapes the scoped directory", path.display()),
+                    ))
+                }
+            }
+        }
+        Ok(PathId::from(inner))
+    }
+
+    fn scoped_path(&self, inner: &PathId) -> io::Result<PathId> {
+        let relative = inner.strip_prefix(&*self.base).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is outside of the scoped directory", inner.display()),
+            )
+        })?;
+        Ok(PathId::from(PathId::root().join(relative)))
+    }
+}
+
+impl<M> FileManager for ScopedFileManager<M>
+where
+    M: FileManager,
+{
+    type File = ScopedFile<M>;
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        let file = self.inner.open(&self.inner_path(path)?, options)?;
+        Ok(ScopedFile {
+            file,
+            path: path.clone(),
+        })
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        self.inner_path(path)
+            .is_ok_and(|path| self.inner.exists(&path))
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        self.inner.create_dir_all(&self.inner_path(path)?)
+    }
+
+    fn remove_dir_all(&self, path: &PathId) -> io::Result<()> {
+        let inner_path = self.inner_path(path)?;
+        if inner_path != self.base {
+            return self.inner.remove_dir_all(&inner_path);
+        }
+
+        // The base directory itself must be kept, as it is this manager's
+        // root.
+        for entry in self.inner.list(&inner_path)? {
+            if self.inner.list(&entry).is_ok() {
+                self.inner.remove_dir_all(&entry)?;
+            } else {
+                self.inner.remove_file(&entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        self.inner.remove_file(&self.inner_path(path)?)
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        self.inner
+            .rename(&self.inner_path(from)?, self.inner_path(&to)?)
+    }
+
+    fn new_fsync_batch(&self) -> io::Result<crate::FSyncBatch<Self>> {
+        Ok(self.fsyncs.new_batch()?)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        // The wrapped manager may still be in use, so only this manager's
+        // fsync threads are stopped.
+        self.fsyncs.shutdown()?;
+        Ok(())
+    }
+
+    fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        self.inner
+            .list(&self.inner_path(path)?)?
+            .iter()
+            .map(|entry| self.scoped_path(entry))
+            .collect()
+    }
+}
+
+/// A file opened by a [`ScopedFileManager`].
+#[derive(Debug)]
+pub struct ScopedFile<M>
+where
+    M: FileManager,
+{
+    file: M::File,
+    path: PathId,
+}
+
+impl<M> ScopedFile<M>
+where
+    M: FileManager,
+{
+    /// Returns the file opened by the wrapped manager.
+    pub fn inner(&self) -> &M::File {
+        &self.file
+    }
+}
+
+impl<M> File for ScopedFile<M>
+where
+    M: FileManager,
+{
+    type Manager = ScopedFileManager<M>;
+
+    fn path(&self) -> &PathId {
+        &self.path
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        self.file.set_len(new_length)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            path: self.path.clone(),
+        })
+    }
+}
+
+impl<M> Read for ScopedFile<M>
+where
+    M: FileManager,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl<M> Write for ScopedFile<M>
+where
+    M: FileManager,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<M> Seek for ScopedFile<M>
+where
+    M: FileManager,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
@@ -1,8 +1,24 @@
-use crate::{fs::StdFileManager, memory::MemoryFileManager};
-use crate::{FileManager, OpenOptions, PathId};
+use crate::checksummed::ChecksummedFileManager;
+use crate::explorer::{CrashExplorer, CrashPoint};
+use crate::fs::StdFileManager;
+use crate::memory::{
+    BitRot, Fault, FaultSchedule, Latency, LatencyProfile, MemoryFileManager, OperationKind,
+    PartialIo, RegionAccess, TornWrites,
+};
+use crate::metrics::{MetricsFileManager, Operation};
+use crate::overlay::OverlayFileManager;
+use crate::read_only::ReadOnlyFileManager;
+use crate::scoped::ScopedFileManager;
+use crate::trace::{replay, TracingFileManager};
+use crate::{
+    copy_tree, copy_tree_incremental, diff_trees, File, FileManager, ModifiedFile, OpenOptions,
+    PathId,
+};
 
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 fn create_read_delete_file<M: FileManager>(manager: M, path: &Path) {
     let file_path = PathId::from(path.join("a-file"));
@@ -102,3 +118,1631 @@ fn create_dir_all_std() {
     let dir = tempfile::tempdir().unwrap();
     create_dir_all(StdFileManager::default(), dir.path());
 }
+
+#[test]
+fn create_read_delete_file_scoped() {
+    let dir = tempfile::tempdir().unwrap();
+    let manager = ScopedFileManager::new(StdFileManager::default(), PathId::from(dir.path()));
+    create_read_delete_file(manager, Path::new("/"));
+}
+
+#[test]
+fn create_dir_all_scoped() {
+    let dir = tempfile::tempdir().unwrap();
+    let manager = ScopedFileManager::new(StdFileManager::default(), PathId::from(dir.path()));
+    create_dir_all(manager, Path::new("/"));
+    // Removing the scoped root keeps the base directory.
+    assert!(dir.path().exists());
+}
+
+#[test]
+fn scoped_paths() {
+    let memory = MemoryFileManager::default();
+    memory.create_dir_all(&PathId::from("/base/db")).unwrap();
+    let scoped = ScopedFileManager::new(memory.clone(), PathId::from("/base"));
+
+    scoped
+        .open(
+            &PathId::from("/db/../db/./wal"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+    assert!(memory.exists(&PathId::from("/base/db/wal")));
+    assert_eq!(
+        scoped.list(&PathId::from("/db")).unwrap(),
+        [PathId::from("/db/wal")]
+    );
+
+    let err = scoped
+        .open(
+            &PathId::from("/db/../../escaped"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(!scoped.exists(&PathId::from("/../base")));
+    assert!(!memory.exists(&PathId::from("/escaped")));
+}
+
+#[test]
+fn memory_maximum_size() {
+    let manager = MemoryFileManager::with_maximum_size(16);
+    let path = PathId::from("/a-file");
+    let mut file = manager
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap();
+
+    // The first write fits entirely, the second write is cut short, and
+    // subsequent writes fail.
+    assert_eq!(file.write(b"hello world").unwrap(), 11);
+    assert_eq!(file.write(b"hello world").unwrap(), 5);
+    assert_eq!(manager.used_size(), 16);
+    let err = file.write(b"!").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::StorageFull);
+    let err = file.set_len(17).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::StorageFull);
+
+    // Overwriting existing bytes doesn't need more space.
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"HELLO").unwrap();
+
+    // Shrinking the file frees space.
+    file.set_len(8).unwrap();
+    assert_eq!(manager.used_size(), 8);
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(b"12345678").unwrap();
+    assert_eq!(manager.used_size(), 16);
+
+    // Removing the file only frees its space once all handles are dropped.
+    manager.remove_file(&path).unwrap();
+    assert_eq!(manager.used_size(), 16);
+    drop(file);
+    assert_eq!(manager.used_size(), 0);
+}
+
+#[test]
+fn memory_bad_regions() {
+    let manager = MemoryFileManager::default();
+    let path = PathId::from("/a-file");
+    let mut file = manager
+        .open(
+            &path,
+            OpenOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+    file.write_all(&[0; 16]).unwrap();
+
+    manager
+        .add_bad_region(&path, 4..8, RegionAccess::Read, ErrorKind::InvalidData)
+        .unwrap();
+    manager
+        .add_bad_region(&path, 12..20, RegionAccess::Write, ErrorKind::Other)
+        .unwrap();
+
+    // Reads before the bad region succeed, but reads touching it fail.
+    let mut buffer = [0; 4];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    let err = file.read_exact(&mut buffer).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    // Writing into a read-only bad region is fine.
+    file.seek(SeekFrom::Start(4)).unwrap();
+    file.write_all(&[1; 4]).unwrap();
+
+    // The write region extends beyond the end of the file.
+    file.seek(SeekFrom::Start(11)).unwrap();
+    let err = file.write(&[1; 2]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    file.seek(SeekFrom::Start(12)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    file.seek(SeekFrom::Start(18)).unwrap();
+    assert_eq!(file.write(&[1; 2]).unwrap_err().kind(), ErrorKind::Other);
+    // The failed write didn't grow the file.
+    assert_eq!(file.len().unwrap(), 16);
+    // Only the bytes actually written are checked.
+    manager.set_partial_io(Some(PartialIo::new(0).at_most(1)));
+    file.seek(SeekFrom::Start(11)).unwrap();
+    assert_eq!(file.write(&[1; 2]).unwrap(), 1);
+    manager.set_partial_io(None);
+    file.seek(SeekFrom::Start(20)).unwrap();
+    file.write_all(&[1; 2]).unwrap();
+
+    manager.clear_bad_regions(&path).unwrap();
+    file.seek(SeekFrom::Start(4)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    assert_eq!(buffer, [1; 4]);
+}
+
+#[test]
+fn memory_crash() {
+    let manager = MemoryFileManager::default();
+    let synced = PathId::from("/synced");
+    let unsynced = PathId::from("/unsynced");
+
+    let mut file = manager
+        .open(&synced, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    file.write_all(b"hello").unwrap();
+    file.sync_data().unwrap();
+    file.write_all(b" world").unwrap();
+    drop(file);
+
+    let mut file = manager
+        .open(&unsynced, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    file.write_all(b"lost").unwrap();
+    drop(file);
+
+    // Make the new files' directory entries durable.
+    manager.sync_all(&PathId::root()).unwrap();
+    manager.crash().unwrap();
+
+    let mut contents = Vec::new();
+    manager
+        .open(&synced, OpenOptions::new().read(true))
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"hello");
+    assert_eq!(
+        manager
+            .open(&unsynced, OpenOptions::new().read(true))
+            .unwrap()
+            .len()
+            .unwrap(),
+        0
+    );
+    assert_eq!(manager.used_size(), 5);
+}
+
+#[test]
+fn memory_crash_after_length_changes() {
+    let manager = MemoryFileManager::default();
+    let path = PathId::from("/file");
+    let mut file = manager
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    manager.sync_all(&PathId::root()).unwrap();
+
+    // Only the changes made since the last sync are lost, including bytes
+    // removed by shrinking the file and then growing it again.
+    file.write_all(b"hello world").unwrap();
+    file.sync_data().unwrap();
+    file.set_len(2).unwrap();
+    file.set_len(5).unwrap();
+    file.seek(SeekFrom::Start(6)).unwrap();
+    file.write_all(b"W").unwrap();
+    manager.crash().unwrap();
+    assert_eq!(read_file(&manager, &path), b"hello world");
+
+    // Syncing after the same changes persists all of them.
+    let mut file = manager.open(&path, OpenOptions::new().write(true)).unwrap();
+    file.set_len(2).unwrap();
+    file.set_len(5).unwrap();
+    file.seek(SeekFrom::Start(5)).unwrap();
+    file.write_all(b"!").unwrap();
+    file.seek(SeekFrom::Start(7)).unwrap();
+    file.write_all(b"W").unwrap();
+    file.sync_data().unwrap();
+    manager.crash().unwrap();
+    assert_eq!(read_file(&manager, &path), b"he\0\0\0!\0W");
+    assert_eq!(manager.used_size(), 8);
+}
+
+#[test]
+fn memory_crash_directory_entries() {
+    let manager = MemoryFileManager::default();
+    let root = PathId::root();
+    let a_file = PathId::from("/a-file");
+    let renamed = PathId::from("/renamed");
+    let a = PathId::from("/a");
+    let a_b = PathId::from("/a/b");
+
+    // Files are lost if their directory isn't synced, even if the file was.
+    let file = manager
+        .open(&a_file, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    file.sync_all().unwrap();
+    manager.crash().unwrap();
+    assert!(!manager.exists(&a_file));
+
+    manager
+        .open(&a_file, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    manager.create_dir_all(&a_b).unwrap();
+    manager.sync_all(&root).unwrap();
+
+    // Renames and removals are reverted until the directory is synced.
+    manager.rename(&a_file, renamed.clone()).unwrap();
+    manager.remove_dir_all(&a).unwrap();
+    manager.crash().unwrap();
+    assert!(manager.exists(&a_file));
+    assert!(!manager.exists(&renamed));
+    // /a was made durable when / was synced, but /a/b wasn't.
+    assert!(manager.exists(&a));
+    assert!(!manager.exists(&a_b));
+
+    manager.rename(&a_file, renamed.clone()).unwrap();
+    manager.remove_dir_all(&a).unwrap();
+    manager.sync_all(&root).unwrap();
+    manager.crash().unwrap();
+    assert!(!manager.exists(&a_file));
+    assert!(manager.exists(&renamed));
+    assert!(!manager.exists(&a));
+    assert_eq!(manager.list(&root).unwrap(), vec![renamed]);
+}
+
+#[test]
+fn memory_torn_writes() {
+    fn crash_with_seed(seed: u64) -> Vec<u8> {
+        let manager = MemoryFileManager::default();
+        manager.set_torn_writes(Some(TornWrites {
+            sector_size: 512,
+            seed,
+        }));
+        let path = PathId::from("/a-file");
+        let mut file = manager
+            .open(&path, OpenOptions::new().write(true).create(true))
+            .unwrap();
+        file.write_all(&[0; 512 * 8]).unwrap();
+        file.sync_all().unwrap();
+        manager.sync_all(&PathId::root()).unwrap();
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[1; 512 * 8]).unwrap();
+        manager.crash().unwrap();
+
+        let mut contents = Vec::new();
+        manager
+            .open(&path, OpenOptions::new().read(true))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    let contents = crash_with_seed(1);
+    assert_eq!(contents.len(), 512 * 8);
+    // Each sector was either entirely persisted or entirely discarded.
+    for sector in contents.chunks(512) {
+        assert!(sector.iter().all(|&byte| byte == sector[0]));
+    }
+    // Some sectors were persisted and others weren't.
+    assert!(contents.contains(&0));
+    assert!(contents.contains(&1));
+    // The outcome is reproducible.
+    assert_eq!(crash_with_seed(1), contents);
+}
+
+fn atomic_replace(manager: &MemoryFileManager, sync_before_rename: bool) {
+    let temporary = PathId::from("/data.tmp");
+    let mut file = manager
+        .open(&temporary, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    file.write_all(b"hello").unwrap();
+    file.write_all(b" world").unwrap();
+    if sync_before_rename {
+        file.sync_all().unwrap();
+    }
+    manager.rename(&temporary, PathId::from("/data")).unwrap();
+    manager.sync_all(&PathId::root()).unwrap();
+}
+
+fn check_atomic_replace(manager: &MemoryFileManager, _crash: &CrashPoint) -> Result<(), String> {
+    let data = PathId::from("/data");
+    if manager.exists(&data) {
+        let mut contents = Vec::new();
+        manager
+            .open(&data, OpenOptions::new().read(true))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        if contents != b"hello world" {
+            return Err(format!("unexpected contents: {contents:?}"));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_explorer() {
+    let states = CrashExplorer::new()
+        .explore(
+            |manager| atomic_replace(manager, true),
+            check_atomic_replace,
+        )
+        .unwrap();
+    assert!(states > 6);
+
+    // Without syncing the file before renaming it, the rename can be
+    // persisted without the file's contents.
+    let inconsistency = CrashExplorer::new()
+        .explore(
+            |manager| atomic_replace(manager, false),
+            check_atomic_replace,
+        )
+        .unwrap_err();
+    // The first inconsistency is found before the directory is synced, when
+    // the creation and rename were persisted but the writes weren't.
+    assert_eq!(inconsistency.crash_point.completed, 4);
+    assert_eq!(inconsistency.crash_point.persisted_unsynced, vec![0, 3]);
+}
+
+#[test]
+fn crash_explorer_reorders_writes() {
+    let path = PathId::from("/a-file");
+    let mut states = HashSet::new();
+    CrashExplorer::new()
+        .explore(
+            |manager| {
+                let mut file = manager
+                    .open(&path, OpenOptions::new().write(true).create(true))
+                    .unwrap();
+                manager.sync_all(&PathId::root()).unwrap();
+                file.write_all(b"aaaa").unwrap();
+                file.seek(SeekFrom::Start(2)).unwrap();
+                file.write_all(b"bbbb").unwrap();
+                // Writing nothing beyond the end of the file fills the gap.
+                file.seek(SeekFrom::Start(8)).unwrap();
+                assert_eq!(file.write(&[]).unwrap(), 0);
+            },
+            |manager, _crash| {
+                if manager.exists(&path) {
+                    states.insert(read_file(manager, &path));
+                }
+                Ok::<_, String>(())
+            },
+        )
+        .unwrap();
+
+    // The second write can be persisted before the first one.
+    assert!(states.contains(b"aabbbb".as_slice()));
+    assert!(states.contains(b"aaaabb".as_slice()));
+    assert!(states.contains(b"aabbbb\0\0".as_slice()));
+    assert!(states.contains(b"\0\0\0\0\0\0\0\0".as_slice()));
+}
+
+#[test]
+fn crash_explorer_limits_reorderings() {
+    let path = PathId::from("/a-file");
+    let mut states = HashSet::new();
+    let checked = CrashExplorer::new()
+        .explore(
+            |manager| {
+                let mut file = manager
+                    .open(&path, OpenOptions::new().write(true).create(true))
+                    .unwrap();
+                manager.sync_all(&PathId::root()).unwrap();
+                file.write_all(b"xxxxxxxx").unwrap();
+                file.sync_all().unwrap();
+                // Every write overlaps the others, so every subset of them
+                // could be persisted in any order.
+                for length in 1..=8 {
+                    file.seek(SeekFrom::Start(0)).unwrap();
+                    file.write_all(&vec![b'0' + length - 1; usize::from(length)])
+                        .unwrap();
+                }
+            },
+            |manager, _crash| {
+                if manager.exists(&path) {
+                    states.insert(read_file(manager, &path));
+                }
+                Ok::<_, String>(())
+            },
+        )
+        .unwrap();
+
+    // Each of the 13 crash points explores at most 2^10 states, instead of
+    // the 109,601 orders of every subset of the last crash point's writes.
+    assert!(checked <= 13 * 1024, "{checked} states checked");
+    // The writes are still explored in reverse.
+    assert!(states.contains(b"01234567".as_slice()));
+    assert!(states.contains(b"77777777".as_slice()));
+}
+
+#[test]
+fn memory_fault_schedule() {
+    let manager = MemoryFileManager::default();
+    manager.set_fault_schedule(Some(
+        FaultSchedule::new(0)
+            .with(Fault::nth(OperationKind::Write, 3, ErrorKind::Other))
+            .with(Fault::nth(
+                OperationKind::Rename,
+                1,
+                ErrorKind::PermissionDenied,
+            ))
+            .with(
+                Fault::every(OperationKind::SyncData, ErrorKind::TimedOut)
+                    .matching(|path| path.extension().is_some_and(|ext| ext == "wal")),
+            ),
+    ));
+
+    let wal = PathId::from("/a.wal");
+    let data = PathId::from("/data");
+    let mut file = manager
+        .open(&wal, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    file.write_all(b"1").unwrap();
+    file.write_all(b"2").unwrap();
+    assert_eq!(file.write(b"3").unwrap_err().kind(), ErrorKind::Other);
+    file.write_all(b"3").unwrap();
+    assert_eq!(file.sync_data().unwrap_err().kind(), ErrorKind::TimedOut);
+    assert_eq!(file.sync_data().unwrap_err().kind(), ErrorKind::TimedOut);
+    file.sync_all().unwrap();
+
+    let data_file = manager
+        .open(&data, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    data_file.sync_data().unwrap();
+
+    assert_eq!(
+        manager
+            .rename(&data, PathId::from("/renamed"))
+            .unwrap_err()
+            .kind(),
+        ErrorKind::PermissionDenied
+    );
+    // The failed operation had no effect.
+    assert!(manager.exists(&data));
+    manager.rename(&data, PathId::from("/renamed")).unwrap();
+}
+
+#[test]
+fn memory_random_faults_are_reproducible() {
+    fn failed_writes(seed: u64) -> Vec<usize> {
+        let manager = MemoryFileManager::default();
+        manager.set_fault_schedule(Some(FaultSchedule::new(seed).with(Fault::random(
+            OperationKind::Write,
+            0.25,
+            ErrorKind::Other,
+        ))));
+        let mut file = manager
+            .open(
+                &PathId::from("/a-file"),
+                OpenOptions::new().write(true).create(true),
+            )
+            .unwrap();
+        (0..100).filter(|_| file.write(b"a").is_err()).collect()
+    }
+
+    let failed = failed_writes(42);
+    assert!(!failed.is_empty() && failed.len() < 100);
+    assert_eq!(failed_writes(42), failed);
+    assert_ne!(failed_writes(43), failed);
+}
+
+#[test]
+fn memory_partial_io() {
+    let manager = MemoryFileManager::default();
+    let path = PathId::from("/a-file");
+    let mut file = manager
+        .open(
+            &path,
+            OpenOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+
+    manager.set_partial_io(Some(PartialIo::new(0).at_most(3).interrupt_every(2)));
+    assert_eq!(file.write(b"hello world").unwrap(), 3);
+    let err = file.write(b"lo world").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+    // write_all retries after short writes and interruptions.
+    file.write_all(b"lo world").unwrap();
+
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut buffer = [0; 11];
+    let bytes_read = loop {
+        match file.read(&mut buffer) {
+            Ok(bytes_read) => break bytes_read,
+            Err(err) => assert_eq!(err.kind(), ErrorKind::Interrupted),
+        }
+    };
+    assert_eq!(bytes_read, 3);
+
+    manager.set_partial_io(Some(
+        PartialIo::new(1).random_lengths().interrupt_randomly(0.5),
+    ));
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"hello world");
+}
+
+#[test]
+fn memory_sync_failure_discards_writes() {
+    let manager = MemoryFileManager::default();
+    manager.set_sync_failure_discards_writes(true);
+    manager.set_fault_schedule(Some(FaultSchedule::new(0).with(Fault::nth(
+        OperationKind::SyncData,
+        2,
+        ErrorKind::Other,
+    ))));
+    let path = PathId::from("/a-file");
+    let mut file = manager
+        .open(
+            &path,
+            OpenOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+    file.write_all(b"hello").unwrap();
+    file.sync_data().unwrap();
+    file.write_all(b" world").unwrap();
+
+    // The failed sync drops the unsynced write, and retrying succeeds.
+    file.sync_data().unwrap_err();
+    file.sync_data().unwrap();
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"hello");
+}
+
+#[test]
+fn fsync_batch_reports_errors() {
+    let manager = MemoryFileManager::default();
+    manager.set_fault_schedule(Some(
+        FaultSchedule::new(0).with(
+            Fault::every(OperationKind::SyncAll, ErrorKind::Other)
+                .matching(|path| path.ends_with("bad")),
+        ),
+    ));
+    let good = manager
+        .open(
+            &PathId::from("/good"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+    let bad = manager
+        .open(
+            &PathId::from("/bad"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+
+    let batch = manager.new_fsync_batch().unwrap();
+    batch.queue_fsync_all(good.clone()).unwrap();
+    batch.queue_fsync_all(bad).unwrap();
+    let err = io::Error::from(batch.wait_all().unwrap_err());
+    assert_eq!(err.kind(), ErrorKind::Other);
+
+    // The fsync threads are still running after the failure.
+    let batch = manager.new_fsync_batch().unwrap();
+    batch.queue_fsync_all(good).unwrap();
+    batch.wait_all().unwrap();
+    manager.shutdown().unwrap();
+}
+
+#[test]
+fn memory_latency() {
+    const LATENCY: Duration = Duration::from_millis(20);
+    let manager = MemoryFileManager::default();
+    manager.set_latency_profile(Some(
+        LatencyProfile::new(0)
+            .with(OperationKind::SyncAll, Latency::Fixed(LATENCY))
+            .with(
+                OperationKind::Write,
+                Latency::Uniform {
+                    minimum: Duration::ZERO,
+                    maximum: LATENCY,
+                },
+            ),
+    ));
+    let files = (0..4)
+        .map(|index| {
+            let mut file = manager
+                .open(
+                    &PathId::from(format!("/{index}").as_str()),
+                    OpenOptions::new().write(true).create(true),
+                )
+                .unwrap();
+            file.write_all(b"hello").unwrap();
+            file
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    files[0].sync_all().unwrap();
+    assert!(start.elapsed() >= LATENCY);
+
+    // Syncs in a batch are delayed too. Whether they overlap depends on the
+    // available parallelism, so only the lower bound can be relied on.
+    let start = Instant::now();
+    let batch = manager.new_fsync_batch().unwrap();
+    for file in &files {
+        batch.queue_fsync_all(file.clone()).unwrap();
+    }
+    batch.wait_all().unwrap();
+    assert!(start.elapsed() >= LATENCY);
+
+    // Operations without a configured latency aren't delayed. The latency of
+    // the other operation is large enough that a slow machine can't be
+    // mistaken for it.
+    const LONG_LATENCY: Duration = Duration::from_secs(60);
+    manager.set_latency_profile(Some(
+        LatencyProfile::new(0).with(OperationKind::Write, Latency::Fixed(LONG_LATENCY)),
+    ));
+    let start = Instant::now();
+    files[0].sync_all().unwrap();
+    assert!(start.elapsed() < LONG_LATENCY);
+    manager.shutdown().unwrap();
+}
+
+#[test]
+fn memory_corruption() {
+    let manager = MemoryFileManager::default();
+    let path = PathId::from("/file");
+    let mut file = manager
+        .open(
+            &path,
+            OpenOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+    file.write_all(b"hello world").unwrap();
+    file.sync_all().unwrap();
+    manager.sync_all(&PathId::root()).unwrap();
+
+    manager.flip_bits(&path, 0, 0b0010_0000).unwrap();
+    manager.corrupt_bytes(&path, 6, b"WORLD").unwrap();
+    let err = manager.corrupt_bytes(&path, 8, b"long").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // Open files observe the corruption, and it affects the durable contents.
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"Hello WORLD");
+    drop(file);
+    manager.crash().unwrap();
+    assert_eq!(read_file(&manager, &path), b"Hello WORLD");
+
+    // Random bit rot flips exactly one bit per corrupted read, and is
+    // reproducible.
+    let rot = |seed| {
+        let manager = manager.clone();
+        let path = path.clone();
+        manager.corrupt_bytes(&path, 0, b"hello world").unwrap();
+        manager.set_bit_rot(Some(BitRot::new(seed, 1.0)));
+        let contents = read_file(&manager, &path);
+        manager.set_bit_rot(None);
+        contents
+    };
+    let corrupted = rot(1);
+    let flipped_bits = corrupted
+        .iter()
+        .zip(b"hello world")
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum::<u32>();
+    assert_eq!(flipped_bits, 1);
+    assert_eq!(rot(1), corrupted);
+    // The corruption is permanent.
+    assert_eq!(read_file(&manager, &path), corrupted);
+}
+
+#[test]
+fn memory_snapshot() {
+    let manager = MemoryFileManager::default();
+    manager.create_dir_all(&PathId::from("/dir")).unwrap();
+    let durable = PathId::from("/dir/durable");
+    let mut file = manager
+        .open(&durable, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    file.write_all(b"synced").unwrap();
+    file.sync_all().unwrap();
+    manager.sync_all(&PathId::from("/dir")).unwrap();
+    manager.sync_all(&PathId::root()).unwrap();
+    file.write_all(b" and unsynced").unwrap();
+    let unsynced = PathId::from("/unsynced");
+    manager
+        .open(&unsynced, OpenOptions::new().write(true).create(true))
+        .unwrap()
+        .write_all(b"new")
+        .unwrap();
+
+    let snapshot = manager.snapshot().unwrap();
+    assert_eq!(snapshot.used_size(), manager.used_size());
+    assert_eq!(read_file(&snapshot, &durable), b"synced and unsynced");
+    assert_eq!(read_file(&snapshot, &unsynced), b"new");
+
+    // Changes to either side aren't visible to the other.
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"SYNCED").unwrap();
+    manager.remove_file(&unsynced).unwrap();
+    snapshot
+        .open(&PathId::from("/dir/other"), OpenOptions::new().create(true))
+        .unwrap();
+    assert_eq!(read_file(&snapshot, &durable), b"synced and unsynced");
+    assert!(snapshot.exists(&unsynced));
+    assert_eq!(read_file(&manager, &durable), b"SYNCED and unsynced");
+    assert!(!manager.exists(&PathId::from("/dir/other")));
+
+    // The snapshot kept track of what was durable.
+    snapshot.crash().unwrap();
+    assert_eq!(read_file(&snapshot, &durable), b"synced");
+    assert_eq!(snapshot.list(&PathId::from("/dir")).unwrap(), [durable]);
+    assert!(!snapshot.exists(&unsynced));
+}
+
+#[test]
+fn memory_checkpoints() {
+    let manager = MemoryFileManager::default();
+    let kept = PathId::from("/kept");
+    let removed = PathId::from("/removed");
+    let created = PathId::from("/created");
+    let mut file = manager
+        .open(
+            &kept,
+            OpenOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+    file.write_all(b"before").unwrap();
+    file.sync_all().unwrap();
+    manager
+        .open(&removed, OpenOptions::new().write(true).create(true))
+        .unwrap()
+        .write_all(b"removed")
+        .unwrap();
+    manager.sync_all(&PathId::root()).unwrap();
+    manager.checkpoint("before-migration").unwrap();
+
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"AFTER!!!").unwrap();
+    file.sync_all().unwrap();
+    manager.remove_file(&removed).unwrap();
+    manager
+        .open(&created, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    manager.sync_all(&PathId::root()).unwrap();
+
+    // Rolling back can be repeated, and open files see the old contents.
+    for _ in 0..2 {
+        manager.rollback_to("before-migration").unwrap();
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"before");
+        assert_eq!(read_file(&manager, &removed), b"removed");
+        assert!(!manager.exists(&created));
+
+        manager.remove_file(&kept).unwrap();
+    }
+
+    // What was durable is restored too.
+    manager.rollback_to("before-migration").unwrap();
+    manager.crash().unwrap();
+    assert_eq!(read_file(&manager, &kept), b"before");
+    assert_eq!(read_file(&manager, &removed), b"");
+    assert!(!manager.exists(&created));
+
+    assert!(manager.remove_checkpoint("before-migration"));
+    let err = manager.rollback_to("before-migration").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn memory_import_export() {
+    let source = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(source.path().join("a/b")).unwrap();
+    std::fs::write(source.path().join("a/file"), b"hello").unwrap();
+    std::fs::write(source.path().join("a/b/file"), b"world").unwrap();
+
+    let manager = MemoryFileManager::default();
+    let imported = PathId::from("/data/imported");
+    manager
+        .import(
+            &StdFileManager::default(),
+            &PathId::from(source.path()),
+            &imported,
+        )
+        .unwrap();
+    // Imported data is durable.
+    manager.crash().unwrap();
+    assert_eq!(
+        read_file(&manager, &PathId::from("/data/imported/a/file")),
+        b"hello"
+    );
+    assert_eq!(
+        read_file(&manager, &PathId::from("/data/imported/a/b/file")),
+        b"world"
+    );
+
+    let mut file = manager
+        .open(
+            &PathId::from("/data/imported/a/file"),
+            OpenOptions::new().write(true),
+        )
+        .unwrap();
+    file.write_all(b"HI").unwrap();
+    file.set_len(2).unwrap();
+    drop(file);
+
+    // Exporting over the original directory overwrites existing files.
+    manager
+        .export(
+            &imported,
+            &StdFileManager::default(),
+            &PathId::from(source.path()),
+        )
+        .unwrap();
+    assert_eq!(std::fs::read(source.path().join("a/file")).unwrap(), b"HI");
+    assert_eq!(
+        std::fs::read(source.path().join("a/b/file")).unwrap(),
+        b"world"
+    );
+}
+
+#[test]
+#[cfg(feature = "archive")]
+fn memory_archive() {
+    let manager = MemoryFileManager::default();
+    manager.create_dir_all(&PathId::from("/a/empty")).unwrap();
+    manager
+        .open(
+            &PathId::from("/a/file"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap()
+        .write_all(b"hello")
+        .unwrap();
+    manager
+        .open(&PathId::from("/b"), OpenOptions::new().create(true))
+        .unwrap();
+
+    let archive = manager.save_archive(Vec::new()).unwrap();
+    // Archives are reproducible.
+    assert_eq!(manager.save_archive(Vec::new()).unwrap(), archive);
+
+    let loaded = MemoryFileManager::load_archive(archive.as_slice()).unwrap();
+    // Loaded data is durable.
+    loaded.crash().unwrap();
+    assert_eq!(read_file(&loaded, &PathId::from("/a/file")), b"hello");
+    assert_eq!(read_file(&loaded, &PathId::from("/b")), b"");
+    assert_eq!(
+        loaded.list(&PathId::from("/a/empty")).unwrap(),
+        Vec::<PathId>::new()
+    );
+    assert_eq!(loaded.save_archive(Vec::new()).unwrap(), archive);
+
+    // A repeated entry replaces the earlier contents, even if they're longer.
+    let mut builder = tar::Builder::new(Vec::new());
+    for contents in [b"hello world".as_slice(), b"bye"] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        builder.append_data(&mut header, "file", contents).unwrap();
+    }
+    let loaded = MemoryFileManager::load_archive(builder.into_inner().unwrap().as_slice()).unwrap();
+    assert_eq!(read_file(&loaded, &PathId::from("/file")), b"bye");
+
+    // Pax headers, as written by many tar implementations, are skipped.
+    let mut builder = tar::Builder::new(Vec::new());
+    let record = b"19 comment=skipped\n";
+    for entry_type in [tar::EntryType::XGlobalHeader, tar::EntryType::XHeader] {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_size(record.len() as u64);
+        builder
+            .append_data(&mut header, "pax_header", record.as_slice())
+            .unwrap();
+    }
+    let mut header = tar::Header::new_ustar();
+    header.set_size(5);
+    builder
+        .append_data(&mut header, "file", b"hello".as_slice())
+        .unwrap();
+    let loaded = MemoryFileManager::load_archive(builder.into_inner().unwrap().as_slice()).unwrap();
+    assert_eq!(
+        loaded.list(&PathId::root()).unwrap(),
+        [PathId::from("/file")]
+    );
+    assert_eq!(read_file(&loaded, &PathId::from("/file")), b"hello");
+}
+
+#[test]
+fn copy_tree_between_managers() {
+    let disk = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(disk.path().join("a/b")).unwrap();
+    std::fs::write(disk.path().join("a/one"), b"one").unwrap();
+    std::fs::write(disk.path().join("a/b/two"), b"two").unwrap();
+    let std = StdFileManager::default();
+    let source = PathId::from(disk.path().join("a"));
+
+    let memory = MemoryFileManager::default();
+    let destination = PathId::from("/copy");
+    assert_eq!(copy_tree(&std, &source, &memory, &destination).unwrap(), 2);
+    assert_eq!(read_file(&memory, &PathId::from("/copy/b/two")), b"two");
+
+    // Incremental copies only copy files that differ.
+    assert_eq!(
+        copy_tree_incremental(&std, &source, &memory, &destination).unwrap(),
+        0
+    );
+    std::fs::write(disk.path().join("a/one"), b"ONE").unwrap();
+    std::fs::write(disk.path().join("a/b/two"), b"three").unwrap();
+    std::fs::write(disk.path().join("a/b/new"), b"new").unwrap();
+    assert_eq!(
+        copy_tree_incremental(&std, &source, &memory, &destination).unwrap(),
+        3
+    );
+    assert_eq!(read_file(&memory, &PathId::from("/copy/one")), b"ONE");
+    assert_eq!(read_file(&memory, &PathId::from("/copy/b/two")), b"three");
+    assert_eq!(read_file(&memory, &PathId::from("/copy/b/new")), b"new");
+
+    // Copying back onto disk.
+    let exported = PathId::from(disk.path().join("exported"));
+    assert_eq!(
+        copy_tree(&memory, &destination, &std, &exported).unwrap(),
+        3
+    );
+    assert_eq!(
+        std::fs::read(disk.path().join("exported/b/two")).unwrap(),
+        b"three"
+    );
+}
+
+#[test]
+fn diff_trees_between_managers() {
+    let disk = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(disk.path().join("a/b")).unwrap();
+    std::fs::write(disk.path().join("a/same"), b"same").unwrap();
+    std::fs::write(disk.path().join("a/b/changed"), b"hello world").unwrap();
+    std::fs::write(disk.path().join("a/b/longer"), b"abc").unwrap();
+    std::fs::write(disk.path().join("a/removed"), b"").unwrap();
+    std::fs::write(disk.path().join("a/kind"), b"").unwrap();
+    let std = StdFileManager::default();
+    let source = PathId::from(disk.path().join("a"));
+
+    let memory = MemoryFileManager::default();
+    let copy = PathId::from("/copy");
+    copy_tree(&std, &source, &memory, &copy).unwrap();
+    assert!(diff_trees(&std, &source, &memory, &copy)
+        .unwrap()
+        .is_empty());
+
+    let write = |path: &str, contents: &[u8]| {
+        let mut file = memory
+            .open(
+                &PathId::from(path),
+                OpenOptions::new().write(true).create(true),
+            )
+            .unwrap();
+        file.write_all(contents).unwrap();
+        file.set_len(contents.len() as u64).unwrap();
+    };
+    write("/copy/b/changed", b"hello WORLD");
+    write("/copy/b/longer", b"abcdef");
+    write("/copy/added", b"");
+    memory.remove_file(&PathId::from("/copy/removed")).unwrap();
+    memory.remove_file(&PathId::from("/copy/kind")).unwrap();
+    memory.create_dir_all(&PathId::from("/copy/kind")).unwrap();
+
+    let diff = diff_trees(&std, &source, &memory, &copy).unwrap();
+    assert_eq!(diff.added, [PathBuf::from("added"), PathBuf::from("kind")]);
+    assert_eq!(
+        diff.removed,
+        [PathBuf::from("kind"), PathBuf::from("removed")]
+    );
+    assert_eq!(
+        diff.modified,
+        [
+            ModifiedFile {
+                path: PathBuf::from("b/changed"),
+                first_difference: 6,
+            },
+            ModifiedFile {
+                path: PathBuf::from("b/longer"),
+                first_difference: 3,
+            },
+        ]
+    );
+}
+
+#[test]
+fn overlay() {
+    let disk = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(disk.path().join("db/old")).unwrap();
+    std::fs::write(disk.path().join("db/data"), b"golden").unwrap();
+    std::fs::write(disk.path().join("db/removed"), b"removed").unwrap();
+    std::fs::write(disk.path().join("db/old/file"), b"old").unwrap();
+    let lower = ScopedFileManager::new(StdFileManager::default(), PathId::from(disk.path()));
+    let upper = MemoryFileManager::default();
+    let overlay = OverlayFileManager::new(lower, upper.clone());
+    let read = |path: &str| {
+        let mut contents = Vec::new();
+        overlay
+            .open(&PathId::from(path), OpenOptions::new().read(true))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    };
+
+    // Reading doesn't copy files up, and lower files can't be written to.
+    assert_eq!(read("/db/data"), b"golden");
+    assert!(!upper.exists(&PathId::from("/db/data")));
+    let mut lower_file = overlay
+        .open(&PathId::from("/db/data"), OpenOptions::new().read(true))
+        .unwrap();
+    let err = lower_file.write(b"nope").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    // Opening a file for writing copies it up.
+    let mut file = overlay
+        .open(
+            &PathId::from("/db/data"),
+            OpenOptions::new().read(true).write(true),
+        )
+        .unwrap();
+    file.write_all(b"GOLD").unwrap();
+    assert_eq!(read("/db/data"), b"GOLDen");
+    overlay
+        .open(
+            &PathId::from("/db/new"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+
+    // Renaming over an entry of the other kind, or over a directory that
+    // isn't empty, fails without hiding the destination.
+    let err = overlay
+        .rename(&PathId::from("/db/new"), PathId::from("/db/old"))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::IsADirectory);
+    let err = overlay
+        .rename(&PathId::from("/db/old"), PathId::from("/db/data"))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    overlay.create_dir_all(&PathId::from("/db/empty")).unwrap();
+    let err = overlay
+        .rename(&PathId::from("/db/empty"), PathId::from("/db/old"))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+    overlay.remove_dir_all(&PathId::from("/db/empty")).unwrap();
+    assert_eq!(read("/db/old/file"), b"old");
+    assert_eq!(read("/db/data"), b"GOLDen");
+
+    // Removing and renaming lower entries hides them.
+    overlay.remove_file(&PathId::from("/db/removed")).unwrap();
+    assert!(!overlay.exists(&PathId::from("/db/removed")));
+    overlay
+        .rename(&PathId::from("/db/old"), PathId::from("/db/renamed"))
+        .unwrap();
+    assert!(!overlay.exists(&PathId::from("/db/old/file")));
+    assert_eq!(read("/db/renamed/file"), b"old");
+    let mut entries = overlay.list(&PathId::from("/db")).unwrap();
+    entries.sort_unstable_by(|a, b| a.cmp(b));
+    assert_eq!(
+        entries,
+        ["/db/data", "/db/new", "/db/renamed"].map(PathId::from)
+    );
+
+    // Recreating a removed file doesn't reveal the lower file.
+    overlay
+        .open(
+            &PathId::from("/db/removed"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+    assert_eq!(read("/db/removed"), b"");
+
+    // The original files are untouched.
+    assert_eq!(
+        std::fs::read(disk.path().join("db/data")).unwrap(),
+        b"golden"
+    );
+    assert!(disk.path().join("db/removed").exists());
+    assert!(disk.path().join("db/old/file").exists());
+    assert!(!disk.path().join("db/new").exists());
+}
+
+#[test]
+fn read_only() {
+    let memory = MemoryFileManager::default();
+    let path = PathId::from("/file");
+    memory
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap()
+        .write_all(b"hello")
+        .unwrap();
+    let manager = ReadOnlyFileManager::new(memory.clone());
+
+    let mut file = manager.open(&path, OpenOptions::new().read(true)).unwrap();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"hello");
+    assert_eq!(
+        manager.list(&PathId::root()).unwrap(),
+        std::slice::from_ref(&path)
+    );
+
+    let denied = |result: io::Result<()>| {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    };
+    denied(file.write_all(b"!"));
+    denied(file.set_len(0));
+    denied(
+        manager
+            .open(&path, OpenOptions::new().read(true).write(true))
+            .map(drop),
+    );
+    denied(
+        manager
+            .open(&PathId::from("/new"), OpenOptions::new().create(true))
+            .map(drop),
+    );
+    denied(manager.create_dir_all(&PathId::from("/dir")));
+    denied(manager.remove_file(&path));
+    denied(manager.remove_dir_all(&PathId::root()));
+    denied(manager.rename(&path, PathId::from("/renamed")));
+
+    assert_eq!(read_file(&memory, &path), b"hello");
+    assert_eq!(memory.list(&PathId::root()).unwrap(), [path]);
+}
+
+#[test]
+fn checksummed() {
+    let memory = MemoryFileManager::default();
+    let manager = ChecksummedFileManager::with_block_size(memory.clone(), 16);
+    create_read_delete_file(manager.clone(), Path::new("/"));
+
+    let path = PathId::from("/file");
+    let mut file = manager
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    let mut expected = (0..40).collect::<Vec<u8>>();
+    file.write_all(&expected).unwrap();
+    // The last block is only stored once the file is flushed.
+    assert_eq!(read_file(&memory, &path).len(), 40);
+    file.flush().unwrap();
+    // Each block is stored followed by its checksum.
+    let stored = read_file(&memory, &path);
+    assert_eq!(stored.len(), 52);
+    assert_eq!(&stored[20..36], &expected[16..32]);
+
+    file.seek(SeekFrom::Start(30)).unwrap();
+    file.write_all(b"abcd").unwrap();
+    expected[30..34].copy_from_slice(b"abcd");
+    let mut reader = manager.open(&path, OpenOptions::new().read(true)).unwrap();
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, expected);
+
+    // Clones share the position, and read-only handles can't be written to.
+    let mut clone = reader.try_clone().unwrap();
+    reader.seek(SeekFrom::Start(4)).unwrap();
+    assert_eq!(clone.stream_position().unwrap(), 4);
+    assert_eq!(
+        clone.write(b"!").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+
+    // Corruption is reported with the offset of the affected block.
+    memory.flip_bits(&path, 45, 0x10).unwrap();
+    reader.seek(SeekFrom::Start(8)).unwrap();
+    let mut partial = [0; 24];
+    reader.read_exact(&mut partial).unwrap();
+    assert_eq!(partial[..], expected[8..32]);
+    let error = reader.read_to_end(&mut contents).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), "block at offset 32 is corrupt");
+
+    // A block copied to another position doesn't pass verification.
+    memory.corrupt_bytes(&path, 0, &stored[20..40]).unwrap();
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    let error = reader.read_exact(&mut partial).unwrap_err();
+    assert_eq!(error.to_string(), "block at offset 0 is corrupt");
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn compressed() {
+    use crate::compressed::CompressedFileManager;
+
+    fn read_all<F: File>(file: &mut F) -> Vec<u8> {
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    let memory = MemoryFileManager::default();
+    let manager = CompressedFileManager::with_chunk_size(memory.clone(), 64);
+    create_read_delete_file(manager.clone(), Path::new("/"));
+
+    let path = PathId::from("/file");
+    let mut file = manager
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    let mut expected = b"hello world ".repeat(50);
+    file.write_all(&expected).unwrap();
+    assert_eq!(file.len().unwrap(), 600);
+    assert!(read_file(&memory, &path).len() < 400);
+
+    // Reads and overwrites at any offset.
+    file.seek(SeekFrom::Start(130)).unwrap();
+    let mut partial = [0; 11];
+    file.read_exact(&mut partial).unwrap();
+    assert_eq!(&partial, b"d hello wor");
+    file.seek(SeekFrom::Start(60)).unwrap();
+    file.write_all(b"0123456789").unwrap();
+    expected[60..70].copy_from_slice(b"0123456789");
+    file.seek(SeekFrom::End(100)).unwrap();
+    file.write_all(b"!").unwrap();
+    expected.resize(700, 0);
+    expected.push(b'!');
+    assert_eq!(read_all(&mut file), expected);
+
+    // Truncated bytes don't reappear when extending the file again.
+    file.set_len(650).unwrap();
+    file.set_len(610).unwrap();
+    file.set_len(800).unwrap();
+    expected.truncate(610);
+    expected.resize(800, 0);
+    assert_eq!(read_all(&mut file), expected);
+
+    // Other handles see the changes, including a partially written record
+    // being ignored and then replaced.
+    let mut reader = manager.open(&path, OpenOptions::new().read(true)).unwrap();
+    assert_eq!(read_all(&mut reader), expected);
+    let mut raw = memory.open(&path, OpenOptions::new().write(true)).unwrap();
+    raw.seek(SeekFrom::End(0)).unwrap();
+    raw.write_all(&[0, 1, 2]).unwrap();
+    assert_eq!(read_all(&mut reader), expected);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"HELLO").unwrap();
+    expected[..5].copy_from_slice(b"HELLO");
+    assert_eq!(read_all(&mut reader), expected);
+
+    // Writes through different handles don't discard each other's records.
+    let mut writer = manager.open(&path, OpenOptions::new().write(true)).unwrap();
+    writer.seek(SeekFrom::Start(200)).unwrap();
+    writer.write_all(b"world").unwrap();
+    file.seek(SeekFrom::Start(300)).unwrap();
+    file.write_all(b"WORLD").unwrap();
+    expected[200..205].copy_from_slice(b"world");
+    expected[300..305].copy_from_slice(b"WORLD");
+    assert_eq!(read_all(&mut reader), expected);
+    drop((writer, reader));
+
+    // Compacting keeps open handles working.
+    file.flush().unwrap();
+    let stored = read_file(&memory, &path).len() as u64;
+    let reclaimed = manager.compact(&path).unwrap();
+    assert!(reclaimed > 0);
+    assert_eq!(read_file(&memory, &path).len() as u64, stored - reclaimed);
+    assert_eq!(
+        memory.list(&PathId::root()).unwrap(),
+        std::slice::from_ref(&path)
+    );
+    assert_eq!(read_all(&mut file), expected);
+    file.seek(SeekFrom::Start(5)).unwrap();
+    file.write_all(b"!").unwrap();
+    expected[5] = b'!';
+    let mut reader = manager.open(&path, OpenOptions::new().read(true)).unwrap();
+    assert_eq!(read_all(&mut reader), expected);
+    drop(reader);
+
+    // Open files follow renames, and buffered writes are stored once the last
+    // handle is dropped.
+    let renamed = PathId::from("/renamed");
+    manager.rename(&path, renamed.clone()).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"h").unwrap();
+    expected[0] = b'h';
+    drop(file);
+    assert!(!memory.exists(&path));
+    let mut reader = manager.open(&renamed, OpenOptions::new()).unwrap();
+    assert_eq!(read_all(&mut reader), expected);
+    drop(reader);
+
+    // Syncing never compacts the file. Each version of this chunk is stored
+    // uncompressed, using 81 bytes.
+    let mut file = manager
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    for i in 0..4 {
+        let contents = (0..64).map(|byte| byte ^ i).collect::<Vec<u8>>();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&contents).unwrap();
+        file.sync_all().unwrap();
+    }
+    drop(file);
+    assert_eq!(read_file(&memory, &path).len(), 324);
+
+    // Compacting never overwrites an existing file at its temporary path.
+    let temporary = PathId::from("/file.compacting");
+    memory
+        .open(&temporary, OpenOptions::new().write(true).create(true))
+        .unwrap()
+        .write_all(b"mine")
+        .unwrap();
+    let err = manager.compact(&path).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(read_file(&memory, &temporary), b"mine");
+    memory.remove_file(&temporary).unwrap();
+
+    // Compacting syncs the directory, so the compacted file survives a crash.
+    assert_eq!(manager.compact(&path).unwrap(), 243);
+    memory.crash().unwrap();
+    assert_eq!(read_file(&memory, &path).len(), 81);
+    let mut reader = manager.open(&path, OpenOptions::new()).unwrap();
+    assert_eq!(
+        read_all(&mut reader),
+        (0..64).map(|byte| byte ^ 3).collect::<Vec<u8>>()
+    );
+    drop(reader);
+
+    // Chunk records with impossible indexes or lengths are rejected rather
+    // than trusted.
+    let records = [
+        (1, u64::MAX.to_le_bytes().to_vec()),
+        (9, 65_u32.to_le_bytes().to_vec()),
+    ];
+    for (offset, bytes) in records {
+        let corrupt = PathId::from("/corrupt");
+        memory
+            .open(&corrupt, OpenOptions::new().write(true).create(true))
+            .unwrap()
+            .write_all(&read_file(&memory, &path))
+            .unwrap();
+        memory.corrupt_bytes(&corrupt, offset, &bytes).unwrap();
+        let err = manager
+            .open(&corrupt, OpenOptions::new())
+            .unwrap()
+            .len()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        memory.remove_file(&corrupt).unwrap();
+    }
+}
+
+#[test]
+#[cfg(feature = "encryption")]
+fn encrypted() {
+    use crate::encrypted::EncryptedFileManager;
+
+    let memory = MemoryFileManager::default();
+    let manager = EncryptedFileManager::with_block_size(memory.clone(), &[1; 32], 16);
+    create_read_delete_file(manager.clone(), Path::new("/"));
+
+    let path = PathId::from("/file");
+    let mut file = manager
+        .open(&path, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    let mut expected = (0..40).collect::<Vec<u8>>();
+    file.write_all(&expected).unwrap();
+    assert_eq!(file.len().unwrap(), 40);
+    file.flush().unwrap();
+    // Each of the three blocks is stored with a nonce and tag, after the
+    // file's identifier.
+    assert_eq!(
+        memory
+            .open(&path, OpenOptions::new())
+            .unwrap()
+            .len()
+            .unwrap(),
+        176
+    );
+    assert!(!read_file(&memory, &path)
+        .windows(8)
+        .any(|window| window == &expected[..8]));
+
+    // Overwrite across a block boundary, then write beyond the end.
+    file.seek(SeekFrom::Start(12)).unwrap();
+    file.write_all(b"abcdefgh").unwrap();
+    expected[12..20].copy_from_slice(b"abcdefgh");
+    file.seek(SeekFrom::End(5)).unwrap();
+    file.write_all(b"!").unwrap();
+    expected.extend_from_slice(&[0, 0, 0, 0, 0, b'!']);
+
+    let mut reader = manager.open(&path, OpenOptions::new().read(true)).unwrap();
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, expected);
+    reader.seek(SeekFrom::Start(14)).unwrap();
+    let mut partial = [0; 4];
+    reader.read_exact(&mut partial).unwrap();
+    assert_eq!(&partial, b"cdef");
+
+    file.set_len(18).unwrap();
+    file.set_len(20).unwrap();
+    expected.truncate(18);
+    expected.extend_from_slice(&[0, 0]);
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    contents.clear();
+    reader.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, expected);
+
+    // Tampering is detected when the affected block is read.
+    memory.flip_bits(&path, 76, 1).unwrap();
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    reader.read_exact(&mut partial).unwrap();
+    let error = reader.read_to_end(&mut contents).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), "block at offset 16 is corrupt");
+
+    // A block copied into another file at the same position isn't accepted.
+    let other = PathId::from("/other");
+    let mut other_file = manager
+        .open(&other, OpenOptions::new().write(true).create(true))
+        .unwrap();
+    other_file.write_all(&[0; 16]).unwrap();
+    drop(other_file);
+    let stored = read_file(&memory, &path);
+    memory.corrupt_bytes(&other, 16, &stored[16..72]).unwrap();
+    let error = manager
+        .open(&other, OpenOptions::new().read(true))
+        .unwrap()
+        .read_exact(&mut partial)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "block at offset 0 is corrupt");
+
+    // The wrong key can't read the contents.
+    let wrong_key = EncryptedFileManager::with_block_size(memory, &[2; 32], 16);
+    let error = wrong_key
+        .open(&path, OpenOptions::new().read(true))
+        .unwrap()
+        .read_exact(&mut partial)
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn metrics() {
+    const LATENCY: Duration = Duration::from_millis(5);
+    let memory = MemoryFileManager::default();
+    memory.set_latency_profile(Some(
+        LatencyProfile::new(0).with(OperationKind::SyncAll, Latency::Fixed(LATENCY)),
+    ));
+    let manager = MetricsFileManager::new(memory);
+    let a = PathId::from("/a");
+    let b = PathId::from("/b");
+
+    let mut file = manager
+        .open(&a, OpenOptions::new().read(true).write(true).create(true))
+        .unwrap();
+    file.write_all(b"hello").unwrap();
+    file.write_all(b" world").unwrap();
+    file.sync_all().unwrap();
+    file.seek(SeekFrom::Start(6)).unwrap();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).unwrap();
+    manager.rename(&a, b.clone()).unwrap();
+    manager.list(&PathId::root()).unwrap();
+    manager.open(&a, OpenOptions::new().read(true)).unwrap_err();
+
+    let snapshot = manager.snapshot();
+    assert_eq!(snapshot.count(Operation::Open), 2);
+    assert_eq!(snapshot.count(Operation::Write), 2);
+    assert_eq!(snapshot.bytes(Operation::Write), 11);
+    assert_eq!(snapshot.bytes(Operation::Read), 5);
+    assert_eq!(snapshot.count(Operation::Seek), 1);
+    assert_eq!(snapshot.count(Operation::SyncAll), 1);
+    assert_eq!(snapshot.count(Operation::Rename), 1);
+    assert_eq!(snapshot.count(Operation::List), 1);
+    assert_eq!(snapshot.count(Operation::RemoveFile), 0);
+
+    let opens = snapshot.path(&a, Operation::Open).unwrap();
+    assert_eq!((opens.count, opens.errors), (2, 1));
+    assert_eq!(snapshot.path(&a, Operation::Write).unwrap().bytes, 11);
+    assert!(snapshot.path(&b, Operation::Write).is_none());
+    // Renames are recorded for both paths.
+    assert_eq!(snapshot.path(&a, Operation::Rename).unwrap().count, 1);
+    assert_eq!(snapshot.path(&b, Operation::Rename).unwrap().count, 1);
+
+    let syncs = &snapshot.operations[&Operation::SyncAll].latency;
+    assert_eq!(syncs.count(), 1);
+    assert!(syncs.maximum() >= LATENCY);
+    assert!(syncs.percentile(50.) >= LATENCY);
+    assert!(syncs.percentile(50.) <= syncs.maximum());
+    assert_eq!(syncs.buckets().map(|(_, count)| count).sum::<u64>(), 1);
+
+    // Resetting returns the metrics collected until then.
+    assert_eq!(manager.reset().count(Operation::Open), 2);
+    assert!(manager.snapshot().operations.is_empty());
+    file.sync_data().unwrap();
+    let snapshot = manager.snapshot();
+    assert_eq!(snapshot.count(Operation::SyncData), 1);
+    assert_eq!(snapshot.operations.len(), 1);
+    // The file keeps recording for the path it was opened with.
+    assert_eq!(snapshot.paths.len(), 1);
+    assert_eq!(snapshot.path(&a, Operation::SyncData).unwrap().count, 1);
+}
+
+#[test]
+fn trace_and_replay() {
+    fn workload<M: FileManager>(manager: &M) {
+        let dir = PathId::from("/dir");
+        let path = PathId::from("/dir/file");
+        manager.create_dir_all(&dir).unwrap();
+        let mut file = manager
+            .open(
+                &path,
+                OpenOptions::new().read(true).write(true).create(true),
+            )
+            .unwrap();
+        file.write_all(b"hello world").unwrap();
+        file.sync_all().unwrap();
+        let mut clone = file.try_clone().unwrap();
+        clone.seek(SeekFrom::Start(6)).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(clone.read(&mut buffer).unwrap(), 5);
+        file.len().unwrap();
+        drop((file, clone));
+        manager.list(&dir).unwrap();
+        manager.rename(&path, PathId::from("/dir/renamed")).unwrap();
+        assert!(!manager.exists(&path));
+        manager
+            .open(&path, OpenOptions::new().read(true))
+            .unwrap_err();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let trace_path = dir.path().join("trace");
+    let manager = TracingFileManager::new(
+        MemoryFileManager::default(),
+        std::fs::File::create(&trace_path).unwrap(),
+    )
+    .unwrap();
+    workload(&manager);
+    manager.finish().unwrap();
+    let trace = std::fs::read(&trace_path).unwrap();
+
+    // Replaying against an equivalent manager produces the same results.
+    assert_eq!(
+        replay(trace.as_slice(), &MemoryFileManager::default()).unwrap(),
+        []
+    );
+    // A trace that was cut off is replayed up to the last complete call.
+    assert_eq!(
+        replay(&trace[..trace.len() - 1], &MemoryFileManager::default()).unwrap(),
+        []
+    );
+
+    // A file that already exists changes the results of later calls.
+    let existing = MemoryFileManager::default();
+    existing.create_dir_all(&PathId::from("/dir")).unwrap();
+    existing
+        .open(
+            &PathId::from("/dir/file"),
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap()
+        .write_all(b"hello world!!!")
+        .unwrap();
+    let divergences = replay(trace.as_slice(), &existing).unwrap();
+    let operations = divergences
+        .iter()
+        .map(|divergence| divergence.operation.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(operations, ["read(#1, 16 bytes)", "len(#0)"]);
+    assert_eq!(divergences[1].expected, "Ok(11)");
+    assert_eq!(divergences[1].actual, "Ok(14)");
+
+    let error = replay(&b"not a trace"[..], &existing).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+    // Error kinds that aren't recorded individually are replayed as Other.
+    let failing = || {
+        let manager = MemoryFileManager::default();
+        manager.set_fault_schedule(Some(FaultSchedule::new(0).with(Fault::nth(
+            OperationKind::CreateDirectory,
+            1,
+            ErrorKind::ConnectionAborted,
+        ))));
+        manager
+    };
+    let manager =
+        TracingFileManager::new(failing(), std::fs::File::create(&trace_path).unwrap()).unwrap();
+    manager.create_dir_all(&PathId::from("/dir")).unwrap_err();
+    manager.finish().unwrap();
+    let trace = std::fs::read(&trace_path).unwrap();
+    assert_eq!(replay(trace.as_slice(), &failing()).unwrap(), []);
+}
+
+fn read_file(manager: &MemoryFileManager, path: &PathId) -> Vec<u8> {
+    let mut contents = Vec::new();
+    manager
+        .open(path, OpenOptions::new().read(true))
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    contents
+}
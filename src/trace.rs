@@ -0,0 +1,1195 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::crc32c::crc32c;
+use crate::fsync::FSyncManager;
+use crate::{File, FileManager, OpenOptions, PathId};
+
+const MAGIC: &[u8; 8] = b"fmtrace1";
+
+/// The size of the buffer used to replay reads. The length of a read comes
+/// from the trace, so longer reads are replayed in steps instead of
+/// allocating a buffer of the recorded length.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A [`FileManager`] that records every operation performed using another
+/// manager into a trace.
+///
+/// Each call is recorded with its arguments and result once it completes,
+/// including the bytes passed to [`Write::write()`]. The bytes returned by
+/// [`Read::read()`] are recorded as a length and checksum, which keeps traces
+/// compact while still detecting differences. A trace can be re-executed
+/// against another manager using [`replay()`].
+///
+/// Calls are recorded in the order they complete. When files are used from
+/// multiple threads, replaying the trace performs the same calls, but may not
+/// reproduce their interleaving exactly.
+///
+/// Each call is written to the trace using a single call to
+/// [`Write::write_all()`], so a trace written directly to a file is complete
+/// up to the last call, even if the process is killed. If writing to the trace
+/// fails, tracing stops, and the error is returned by
+/// [`finish()`](Self::finish).
+#[derive(Clone, Debug)]
+pub struct TracingFileManager<M>
+where
+    M: FileManager,
+{
+    inner: M,
+    tracer: Arc<Tracer>,
+    fsyncs: FSyncManager<Self>,
+}
+
+impl<M> TracingFileManager<M>
+where
+    M: FileManager,
+{
+    /// Returns a manager that records the operations performed on `inner` to
+    /// `trace`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any error that occurs while writing the trace's header.
+    pub fn new<W>(inner: M, mut trace: W) -> io::Result<Self>
+    where
+        W: Write + Send + 'static,
+    {
+        trace.write_all(MAGIC)?;
+        Ok(Self {
+            inner,
+            tracer: Arc::new(Tracer(Mutex::new(TracerState {
+                trace: Box::new(trace),
+                next_handle: 0,
+                error: None,
+            }))),
+            fsyncs: FSyncManager::default(),
+        })
+    }
+
+    /// Returns the wrapped manager.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Flushes the trace, and returns the error that stopped tracing, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.tracer.lock();
+        if let Some(error) = state.error.take() {
+            return Err(error);
+        }
+        state.trace.flush()
+    }
+}
+
+impl<M> FileManager for TracingFileManager<M>
+where
+    M: FileManager,
+{
+    type File = TracingFile<M>;
+
+    fn open(&self, path: &PathId, options: OpenOptions) -> io::Result<Self::File> {
+        let flags = OpenFlags::new(&options);
+        let result = self.inner.open(path, options);
+        let handle = self.tracer.allocate_handle();
+        self.tracer.record(
+            Call::Open {
+                path: path.clone(),
+                options: flags,
+                handle,
+            },
+            Outcome::from_result(&result, |_| Outcome::Unit),
+        );
+        Ok(TracingFile {
+            file: result?,
+            handle,
+            tracer: self.tracer.clone(),
+        })
+    }
+
+    fn exists(&self, path: &PathId) -> bool {
+        let exists = self.inner.exists(path);
+        self.tracer
+            .record(Call::Exists { path: path.clone() }, Outcome::Bool(exists));
+        exists
+    }
+
+    fn create_dir_all(&self, path: &PathId) -> io::Result<()> {
+        let result = self.inner.create_dir_all(path);
+        self.tracer
+            .record_unit(Call::CreateDirectory { path: path.clone() }, &result);
+        result
+    }
+
+    fn remove_dir_all(&self, path: &PathId) -> io::Result<()> {
+        let result = self.inner.remove_dir_all(path);
+        self.tracer
+            .record_unit(Call::RemoveDirectory { path: path.clone() }, &result);
+        result
+    }
+
+    fn remove_file(&self, path: &PathId) -> io::Result<()> {
+        let result = self.inner.remove_file(path);
+        self.tracer
+            .record_unit(Call::RemoveFile { path: path.clone() }, &result);
+        result
+    }
+
+    fn rename(&self, from: &PathId, to: PathId) -> io::Result<()> {
+        let result = self.inner.rename(from, to.clone());
+        self.tracer.record_unit(
+            Call::Rename {
+                from: from.clone(),
+                to,
+            },
+            &result,
+        );
+        result
+    }
+
+    fn new_fsync_batch(&self) -> io::Result<crate::FSyncBatch<Self>> {
+        Ok(self.fsyncs.new_batch()?)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.fsyncs.shutdown()?;
+        Ok(())
+    }
+
+    fn list(&self, path: &PathId) -> io::Result<Vec<PathId>> {
+        let result = self.inner.list(path);
+        self.tracer.record(
+            Call::List { path: path.clone() },
+            Outcome::from_result(&result, |entries| Outcome::paths(entries)),
+        );
+        result
+    }
+}
+
+/// A file opened by a [`TracingFileManager`].
+#[derive(Debug)]
+pub struct TracingFile<M>
+where
+    M: FileManager,
+{
+    file: M::File,
+    handle: u64,
+    tracer: Arc<Tracer>,
+}
+
+impl<M> File for TracingFile<M>
+where
+    M: FileManager,
+{
+    type Manager = TracingFileManager<M>;
+
+    fn path(&self) -> &PathId {
+        self.file.path()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        let result = self.file.sync_all();
+        self.tracer.record_unit(
+            Call::SyncAll {
+                handle: self.handle,
+            },
+            &result,
+        );
+        result
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let result = self.file.sync_data();
+        self.tracer.record_unit(
+            Call::SyncData {
+                handle: self.handle,
+            },
+            &result,
+        );
+        result
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        let result = self.file.len();
+        self.tracer.record(
+            Call::Length {
+                handle: self.handle,
+            },
+            Outcome::from_result(&result, |length| Outcome::Count(*length)),
+        );
+        result
+    }
+
+    fn set_len(&self, new_length: u64) -> io::Result<()> {
+        let result = self.file.set_len(new_length);
+        self.tracer.record_unit(
+            Call::SetLength {
+                handle: self.handle,
+                length: new_length,
+            },
+            &result,
+        );
+        result
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        let result = self.file.try_clone();
+        let clone = self.tracer.allocate_handle();
+        self.tracer.record(
+            Call::TryClone {
+                handle: self.handle,
+                clone,
+            },
+            Outcome::from_result(&result, |_| Outcome::Unit),
+        );
+        Ok(Self {
+            file: result?,
+            handle: clone,
+            tracer: self.tracer.clone(),
+        })
+    }
+}
+
+impl<M> Read for TracingFile<M>
+where
+    M: FileManager,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.file.read(buf);
+        self.tracer.record(
+            Call::Read {
+                handle: self.handle,
+                length: buf.len() as u64,
+            },
+            Outcome::from_result(&result, |bytes_read| Outcome::data(&buf[..*bytes_read])),
+        );
+        result
+    }
+}
+
+impl<M> Write for TracingFile<M>
+where
+    M: FileManager,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.file.write(buf);
+        self.tracer.record(
+            Call::Write {
+                handle: self.handle,
+                data: buf.to_vec(),
+            },
+            Outcome::from_result(&result, |bytes_written| {
+                Outcome::Count(*bytes_written as u64)
+            }),
+        );
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.file.flush();
+        self.tracer.record_unit(
+            Call::Flush {
+                handle: self.handle,
+            },
+            &result,
+        );
+        result
+    }
+}
+
+impl<M> Seek for TracingFile<M>
+where
+    M: FileManager,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let result = self.file.seek(pos);
+        self.tracer.record(
+            Call::Seek {
+                handle: self.handle,
+                pos,
+            },
+            Outcome::from_result(&result, |position| Outcome::Count(*position)),
+        );
+        result
+    }
+}
+
+impl<M> Drop for TracingFile<M>
+where
+    M: FileManager,
+{
+    fn drop(&mut self) {
+        self.tracer.record(
+            Call::Close {
+                handle: self.handle,
+            },
+            Outcome::Unit,
+        );
+    }
+}
+
+struct Tracer(Mutex<TracerState>);
+
+struct TracerState {
+    trace: Box<dyn Write + Send>,
+    next_handle: u64,
+    error: Option<io::Error>,
+}
+
+impl Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Tracer").finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    fn lock(&self) -> std::sync::MutexGuard<'_, TracerState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn allocate_handle(&self) -> u64 {
+        let mut state = self.lock();
+        let handle = state.next_handle;
+        state.next_handle += 1;
+        handle
+    }
+
+    fn record(&self, call: Call, outcome: Outcome) {
+        let mut encoded = Vec::new();
+        call.encode(&mut encoded);
+        outcome.encode(&mut encoded);
+
+        let mut state = self.lock();
+        if state.error.is_none() {
+            if let Err(err) = state.trace.write_all(&encoded) {
+                state.error = Some(err);
+            }
+        }
+    }
+
+    fn record_unit(&self, call: Call, result: &io::Result<()>) {
+        self.record(call, Outcome::from_result(result, |()| Outcome::Unit));
+    }
+}
+
+/// A difference between the result of an operation in a trace and the result
+/// of performing it again using [`replay()`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Divergence {
+    /// The position of the operation in the trace, starting at 0.
+    pub index: u64,
+    /// A description of the operation, such as `write(#2, 11 bytes)`. File
+    /// handles are numbered in the order they were opened.
+    pub operation: String,
+    /// The result that was recorded in the trace.
+    pub expected: String,
+    /// The result of replaying the operation.
+    pub actual: String,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "operation {} {}: expected {}, got {}",
+            self.index, self.operation, self.expected, self.actual
+        )
+    }
+}
+
+/// Performs every operation recorded in `trace` by a [`TracingFileManager`]
+/// using `manager`, and returns each operation whose result differs from the
+/// recorded result.
+///
+/// Replaying continues after a divergence. Operations on a file that was
+/// opened in the trace, but that failed to open while replaying, are reported
+/// as divergences as well. A trace that ends partway through an operation,
+/// such as when the traced process was killed, is replayed up to the last
+/// complete operation.
+///
+/// # Errors
+///
+/// Returns an error if `trace` can't be read, or [`ErrorKind::InvalidData`]
+/// if it isn't a valid trace.
+pub fn replay<M, R>(mut trace: R, manager: &M) -> io::Result<Vec<Divergence>>
+where
+    M: FileManager,
+    R: Read,
+{
+    let mut magic = [0; MAGIC.len()];
+    trace.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a trace"));
+    }
+
+    let mut files = HashMap::<u64, M::File>::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut divergences = Vec::new();
+    let mut index = 0;
+    while let Some(tag) = read_tag(&mut trace)? {
+        let (call, expected) = match Call::decode(tag, &mut trace)
+            .and_then(|call| Ok((call, Outcome::decode(&mut trace)?)))
+        {
+            Ok(event) => event,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+
+        let actual = match call.perform(manager, &mut files, &mut buffer) {
+            Ok(actual) if actual == expected => None,
+            Ok(actual) => Some(actual.to_string()),
+            Err(description) => Some(description),
+        };
+        if let Some(actual) = actual {
+            divergences.push(Divergence {
+                index,
+                operation: call.to_string(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+        index += 1;
+    }
+    Ok(divergences)
+}
+
+/// The [`OpenOptions`] a file was opened with.
+#[derive(Clone, Copy, Debug)]
+struct OpenFlags {
+    read: bool,
+    write: bool,
+    create: bool,
+}
+
+impl OpenFlags {
+    fn new(options: &OpenOptions) -> Self {
+        Self {
+            read: options.read,
+            write: options.write,
+            create: options.create,
+        }
+    }
+
+    fn to_options(self) -> OpenOptions {
+        OpenOptions::new()
+            .read(self.read)
+            .write(self.write)
+            .create(self.create)
+    }
+}
+
+/// Replays a read of `length` bytes using `buffer`. A read longer than
+/// `buffer` is performed as a sequence of reads, which stops at the first read
+/// that returns fewer bytes than requested or fails.
+fn replay_read<F: Read>(file: &mut F, length: u64, buffer: &mut [u8]) -> Outcome {
+    let mut bytes_read = 0;
+    let mut checksum = !0;
+    loop {
+        let requested = usize::try_from(length - bytes_read)
+            .map_or(buffer.len(), |remaining| remaining.min(buffer.len()));
+        match file.read(&mut buffer[..requested]) {
+            Ok(read) => {
+                checksum = crc32c(checksum, &buffer[..read]);
+                bytes_read += read as u64;
+                if read < requested || bytes_read == length {
+                    break;
+                }
+            }
+            Err(err) if bytes_read == 0 => return Outcome::error(err.kind()),
+            Err(_) => break,
+        }
+    }
+
+    Outcome::Data {
+        length: bytes_read,
+        checksum: !checksum,
+    }
+}
+
+/// A call recorded in a trace.
+#[derive(Debug)]
+enum Call {
+    Open {
+        path: PathId,
+        options: OpenFlags,
+        handle: u64,
+    },
+    Exists {
+        path: PathId,
+    },
+    CreateDirectory {
+        path: PathId,
+    },
+    RemoveDirectory {
+        path: PathId,
+    },
+    RemoveFile {
+        path: PathId,
+    },
+    Rename {
+        from: PathId,
+        to: PathId,
+    },
+    List {
+        path: PathId,
+    },
+    Read {
+        handle: u64,
+        length: u64,
+    },
+    Write {
+        handle: u64,
+        data: Vec<u8>,
+    },
+    Flush {
+        handle: u64,
+    },
+    Seek {
+        handle: u64,
+        pos: SeekFrom,
+    },
+    Length {
+        handle: u64,
+    },
+    SetLength {
+        handle: u64,
+        length: u64,
+    },
+    SyncAll {
+        handle: u64,
+    },
+    SyncData {
+        handle: u64,
+    },
+    TryClone {
+        handle: u64,
+        clone: u64,
+    },
+    Close {
+        handle: u64,
+    },
+}
+
+impl Call {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Call::Open {
+                path,
+                options,
+                handle,
+            } => {
+                out.push(0);
+                encode_path(path, out);
+                out.push(
+                    u8::from(options.read)
+                        | u8::from(options.write) << 1
+                        | u8::from(options.create) << 2,
+                );
+                encode_varint(*handle, out);
+            }
+            Call::Exists { path } => {
+                out.push(1);
+                encode_path(path, out);
+            }
+            Call::CreateDirectory { path } => {
+                out.push(2);
+                encode_path(path, out);
+            }
+            Call::RemoveDirectory { path } => {
+                out.push(3);
+                encode_path(path, out);
+            }
+            Call::RemoveFile { path } => {
+                out.push(4);
+                encode_path(path, out);
+            }
+            Call::Rename { from, to } => {
+                out.push(5);
+                encode_path(from, out);
+                encode_path(to, out);
+            }
+            Call::List { path } => {
+                out.push(6);
+                encode_path(path, out);
+            }
+            Call::Read { handle, length } => {
+                out.push(7);
+                encode_varint(*handle, out);
+                encode_varint(*length, out);
+            }
+            Call::Write { handle, data } => {
+                out.push(8);
+                encode_varint(*handle, out);
+                encode_bytes(data, out);
+            }
+            Call::Flush { handle } => {
+                out.push(9);
+                encode_varint(*handle, out);
+            }
+            Call::Seek { handle, pos } => {
+                out.push(10);
+                encode_varint(*handle, out);
+                let (whence, offset) = match pos {
+                    SeekFrom::Start(offset) => (0, *offset as i64),
+                    SeekFrom::End(offset) => (1, *offset),
+                    SeekFrom::Current(offset) => (2, *offset),
+                };
+                out.push(whence);
+                out.extend_from_slice(&offset.to_le_bytes());
+            }
+            Call::Length { handle } => {
+                out.push(11);
+                encode_varint(*handle, out);
+            }
+            Call::SetLength { handle, length } => {
+                out.push(12);
+                encode_varint(*handle, out);
+                encode_varint(*length, out);
+            }
+            Call::SyncAll { handle } => {
+                out.push(13);
+                encode_varint(*handle, out);
+            }
+            Call::SyncData { handle } => {
+                out.push(14);
+                encode_varint(*handle, out);
+            }
+            Call::TryClone { handle, clone } => {
+                out.push(15);
+                encode_varint(*handle, out);
+                encode_varint(*clone, out);
+            }
+            Call::Close { handle } => {
+                out.push(16);
+                encode_varint(*handle, out);
+            }
+        }
+    }
+
+    fn decode<R: Read>(tag: u8, trace: &mut R) -> io::Result<Self> {
+        Ok(match tag {
+            0 => {
+                let path = decode_path(trace)?;
+                let options = read_u8(trace)?;
+                Call::Open {
+                    path,
+                    options: OpenFlags {
+                        read: options & 1 != 0,
+                        write: options & 2 != 0,
+                        create: options & 4 != 0,
+                    },
+                    handle: decode_varint(trace)?,
+                }
+            }
+            1 => Call::Exists {
+                path: decode_path(trace)?,
+            },
+            2 => Call::CreateDirectory {
+                path: decode_path(trace)?,
+            },
+            3 => Call::RemoveDirectory {
+                path: decode_path(trace)?,
+            },
+            4 => Call::RemoveFile {
+                path: decode_path(trace)?,
+            },
+            5 => Call::Rename {
+                from: decode_path(trace)?,
+                to: decode_path(trace)?,
+            },
+            6 => Call::List {
+                path: decode_path(trace)?,
+            },
+            7 => Call::Read {
+                handle: decode_varint(trace)?,
+                length: decode_varint(trace)?,
+            },
+            8 => Call::Write {
+                handle: decode_varint(trace)?,
+                data: decode_bytes(trace)?,
+            },
+            9 => Call::Flush {
+                handle: decode_varint(trace)?,
+            },
+            10 => {
+                let handle = decode_varint(trace)?;
+                let whence = read_u8(trace)?;
+                let mut offset = [0; 8];
+                trace.read_exact(&mut offset)?;
+                let offset = i64::from_le_bytes(offset);
+                let pos = match whence {
+                    0 => SeekFrom::Start(offset as u64),
+                    1 => SeekFrom::End(offset),
+                    2 => SeekFrom::Current(offset),
+                    _ => return Err(invalid_trace()),
+                };
+                Call::Seek { handle, pos }
+            }
+            11 => Call::Length {
+                handle: decode_varint(trace)?,
+            },
+            12 => Call::SetLength {
+                handle: decode_varint(trace)?,
+                length: decode_varint(trace)?,
+            },
+            13 => Call::SyncAll {
+                handle: decode_varint(trace)?,
+            },
+            14 => Call::SyncData {
+                handle: decode_varint(trace)?,
+            },
+            15 => Call::TryClone {
+                handle: decode_varint(trace)?,
+                clone: decode_varint(trace)?,
+            },
+            16 => Call::Close {
+                handle: decode_varint(trace)?,
+            },
+            _ => return Err(invalid_trace()),
+        })
+    }
+
+    /// Performs this call using `manager`, reading into `buffer`. Returns an
+    /// error describing why the call couldn't be performed, such as when the
+    /// file it uses failed to open.
+    fn perform<M: FileManager>(
+        &self,
+        manager: &M,
+        files: &mut HashMap<u64, M::File>,
+        buffer: &mut [u8],
+    ) -> Result<Outcome, String> {
+        fn file<'a, F>(files: &'a mut HashMap<u64, F>, handle: &u64) -> Result<&'a mut F, String> {
+            files
+                .get_mut(handle)
+                .ok_or_else(|| format!("#{handle} isn't open"))
+        }
+
+        Ok(match self {
+            Call::Open {
+                path,
+                options,
+                handle,
+            } => {
+                let result = manager.open(path, options.to_options());
+                let outcome = Outcome::from_result(&result, |_| Outcome::Unit);
+                if let Ok(opened) = result {
+                    files.insert(*handle, opened);
+                }
+                outcome
+            }
+            Call::Exists { path } => Outcome::Bool(manager.exists(path)),
+            Call::CreateDirectory { path } => {
+                Outcome::from_result(&manager.create_dir_all(path), |()| Outcome::Unit)
+            }
+            Call::RemoveDirectory { path } => {
+                Outcome::from_result(&manager.remove_dir_all(path), |()| Outcome::Unit)
+            }
+            Call::RemoveFile { path } => {
+                Outcome::from_result(&manager.remove_file(path), |()| Outcome::Unit)
+            }
+            Call::Rename { from, to } => {
+                Outcome::from_result(&manager.rename(from, to.clone()), |()| Outcome::Unit)
+            }
+            Call::List { path } => {
+                Outcome::from_result(&manager.list(path), |entries| Outcome::paths(entries))
+            }
+            Call::Read { handle, length } => replay_read(file(files, handle)?, *length, buffer),
+            Call::Write { handle, data } => {
+                Outcome::from_result(&file(files, handle)?.write(data), |bytes_written| {
+                    Outcome::Count(*bytes_written as u64)
+                })
+            }
+            Call::Flush { handle } => {
+                Outcome::from_result(&file(files, handle)?.flush(), |()| Outcome::Unit)
+            }
+            Call::Seek { handle, pos } => {
+                Outcome::from_result(&file(files, handle)?.seek(*pos), |position| {
+                    Outcome::Count(*position)
+                })
+            }
+            Call::Length { handle } => {
+                Outcome::from_result(&file(files, handle)?.len(), |length| {
+                    Outcome::Count(*length)
+                })
+            }
+            Call::SetLength { handle, length } => {
+                Outcome::from_result(&file(files, handle)?.set_len(*length), |()| Outcome::Unit)
+            }
+            Call::SyncAll { handle } => {
+                Outcome::from_result(&file(files, handle)?.sync_all(), |()| Outcome::Unit)
+            }
+            Call::SyncData { handle } => {
+                Outcome::from_result(&file(files, handle)?.sync_data(), |()| Outcome::Unit)
+            }
+            Call::TryClone { handle, clone } => {
+                let result = file(files, handle)?.try_clone();
+                let outcome = Outcome::from_result(&result, |_| Outcome::Unit);
+                if let Ok(cloned) = result {
+                    files.insert(*clone, cloned);
+                }
+                outcome
+            }
+            Call::Close { handle } => {
+                files.remove(handle);
+                Outcome::Unit
+            }
+        })
+    }
+}
+
+impl Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Call::Open {
+                path,
+                options,
+                handle,
+            } => {
+                write!(f, "open({}", path.display())?;
+                for (enabled, name) in [
+                    (options.read, "read"),
+                    (options.write, "write"),
+                    (options.create, "create"),
+                ] {
+                    if enabled {
+                        write!(f, ", {name}")?;
+                    }
+                }
+                write!(f, ") as #{handle}")
+            }
+            Call::Exists { path } => write!(f, "exists({})", path.display()),
+            Call::CreateDirectory { path } => write!(f, "create_dir_all({})", path.display()),
+            Call::RemoveDirectory { path } => write!(f, "remove_dir_all({})", path.display()),
+            Call::RemoveFile { path } => write!(f, "remove_file({})", path.display()),
+            Call::Rename { from, to } => {
+                write!(f, "rename({}, {})", from.display(), to.display())
+            }
+            Call::List { path } => write!(f, "list({})", path.display()),
+            Call::Read { handle, length } => write!(f, "read(#{handle}, {length} bytes)"),
+            Call::Write { handle, data } => write!(f, "write(#{handle}, {} bytes)", data.len()),
+            Call::Flush { handle } => write!(f, "flush(#{handle})"),
+            Call::Seek { handle, pos } => write!(f, "seek(#{handle}, {pos:?})"),
+            Call::Length { handle } => write!(f, "len(#{handle})"),
+            Call::SetLength { handle, length } => write!(f, "set_len(#{handle}, {length})"),
+            Call::SyncAll { handle } => write!(f, "sync_all(#{handle})"),
+            Call::SyncData { handle } => write!(f, "sync_data(#{handle})"),
+            Call::TryClone { handle, clone } => write!(f, "try_clone(#{handle}) as #{clone}"),
+            Call::Close { handle } => write!(f, "close(#{handle})"),
+        }
+    }
+}
+
+/// The result of a call recorded in a trace.
+#[derive(Debug, Eq, PartialEq)]
+enum Outcome {
+    Unit,
+    Bool(bool),
+    Count(u64),
+    /// A directory listing, sorted so that managers listing entries in
+    /// different orders are equivalent.
+    Paths(Vec<PathId>),
+    /// The bytes returned by a read.
+    Data {
+        length: u64,
+        checksum: u32,
+    },
+    Error(ErrorKind),
+}
+
+/// The error kinds that are recorded in traces. Any other kind is recorded as
+/// [`ErrorKind::Other`]. Kinds stabilized after Rust 1.83 aren't included.
+const ERROR_KINDS: [ErrorKind; 27] = [
+    ErrorKind::Other,
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::AlreadyExists,
+    ErrorKind::WouldBlock,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::TimedOut,
+    ErrorKind::WriteZero,
+    ErrorKind::Interrupted,
+    ErrorKind::Unsupported,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::OutOfMemory,
+    ErrorKind::NotADirectory,
+    ErrorKind::IsADirectory,
+    ErrorKind::DirectoryNotEmpty,
+    ErrorKind::ReadOnlyFilesystem,
+    ErrorKind::StorageFull,
+    ErrorKind::NotSeekable,
+    ErrorKind::FileTooLarge,
+    ErrorKind::ResourceBusy,
+    ErrorKind::TooManyLinks,
+    ErrorKind::BrokenPipe,
+    ErrorKind::AddrInUse,
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::Deadlock,
+];
+
+impl Outcome {
+    fn from_result<T>(result: &io::Result<T>, ok: impl FnOnce(&T) -> Self) -> Self {
+        match result {
+            Ok(value) => ok(value),
+            Err(err) => Outcome::error(err.kind()),
+        }
+    }
+
+    /// Returns the outcome of an error of `kind`. Kinds that can't be recorded
+    /// are treated as [`ErrorKind::Other`] everywhere, so that replaying them
+    /// doesn't report a divergence.
+    fn error(kind: ErrorKind) -> Self {
+        if ERROR_KINDS.contains(&kind) {
+            Outcome::Error(kind)
+        } else {
+            Outcome::Error(ErrorKind::Other)
+        }
+    }
+
+    fn paths(entries: &[PathId]) -> Self {
+        let mut entries = entries.to_vec();
+        entries.sort_unstable_by(|a, b| a.cmp(b));
+        Outcome::Paths(entries)
+    }
+
+    fn data(bytes: &[u8]) -> Self {
+        Outcome::Data {
+            length: bytes.len() as u64,
+            checksum: !crc32c(!0, bytes),
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Outcome::Unit => out.push(0),
+            Outcome::Bool(value) => {
+                out.push(1);
+                out.push(u8::from(*value));
+            }
+            Outcome::Count(count) => {
+                out.push(2);
+                encode_varint(*count, out);
+            }
+            Outcome::Paths(entries) => {
+                out.push(3);
+                encode_varint(entries.len() as u64, out);
+                for entry in entries {
+                    encode_path(entry, out);
+                }
+            }
+            Outcome::Data { length, checksum } => {
+                out.push(4);
+                encode_varint(*length, out);
+                out.extend_from_slice(&checksum.to_le_bytes());
+            }
+            Outcome::Error(kind) => {
+                out.push(5);
+                out.push(
+                    ERROR_KINDS
+                        .iter()
+                        .position(|known| known == kind)
+                        .unwrap_or(0) as u8,
+                );
+            }
+        }
+    }
+
+    fn decode<R: Read>(trace: &mut R) -> io::Result<Self> {
+        Ok(match read_u8(trace)? {
+            0 => Outcome::Unit,
+            1 => Outcome::Bool(read_u8(trace)? != 0),
+            2 => Outcome::Count(decode_varint(trace)?),
+            3 => {
+                let count = decode_varint(trace)?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push(decode_path(trace)?);
+                }
+                Outcome::Paths(entries)
+            }
+            4 => {
+                let length = decode_varint(trace)?;
+                let mut checksum = [0; 4];
+                trace.read_exact(&mut checksum)?;
+                Outcome::Data {
+                    length,
+                    checksum: u32::from_le_bytes(checksum),
+                }
+            }
+            5 => Outcome::Error(
+                *ERROR_KINDS
+                    .get(usize::from(read_u8(trace)?))
+                    .ok_or_else(invalid_trace)?,
+            ),
+            _ => return Err(invalid_trace()),
+        })
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Unit => f.write_str("Ok(())"),
+            Outcome::Bool(value) => write!(f, "{value}"),
+            Outcome::Count(count) => write!(f, "Ok({count})"),
+            Outcome::Paths(entries) => {
+                let entries = entries.iter().map(|entry| &**entry).collect::<Vec<_>>();
+                write!(f, "Ok({entries:?})")
+            }
+            Outcome::Data { length, checksum } => {
+                write!(f, "Ok({length} bytes with checksum {checksum:08x})")
+            }
+            Outcome::Error(kind) => write!(f, "Err({kind:?})"),
+        }
+    }
+}
+
+fn invalid_trace() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "invalid trace")
+}
+
+/// Reads the tag of the next call, or returns `None` at the end of the trace.
+fn read_tag<R: Read>(trace: &mut R) -> io::Result<Option<u8>> {
+    let mut tag = [0];
+    loop {
+        match trace.read(&mut tag) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(tag[0])),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read_u8<R: Read>(trace: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    trace.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_varint<R: Read>(trace: &mut R) -> io::Result<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(trace)?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_trace())
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    encode_varint(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+fn decode_bytes<R: Read>(trace: &mut R) -> io::Result<Vec<u8>> {
+    let length = decode_varint(trace)?;
+    let mut bytes = Vec::new();
+    trace.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 == length {
+        Ok(bytes)
+    } else {
+        Err(io::Error::from(ErrorKind::UnexpectedEof))
+    }
+}
+
+/// Records the bytes of `path` exactly, so that paths that aren't valid
+/// UTF-8 are replayed unchanged.
+fn encode_path(path: &PathId, out: &mut Vec<u8>) {
+    encode_bytes(path.as_os_str().as_encoded_bytes(), out);
+}
+
+#[cfg(unix)]
+fn decode_path<R: Read>(trace: &mut R) -> io::Result<PathId> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    let bytes = decode_bytes(trace)?;
+    Ok(PathId::from(Path::new(OsStr::from_bytes(&bytes))))
+}
+
+/// Paths that aren't valid UTF-8 can only be rebuilt from their bytes on
+/// Unix, so elsewhere they are treated as an invalid trace.
+#[cfg(not(unix))]
+fn decode_path<R: Read>(trace: &mut R) -> io::Result<PathId> {
+    let path = String::from_utf8(decode_bytes(trace)?).map_err(|_| invalid_trace())?;
+    Ok(PathId::from(path.as_str()))
+}
+
+#[test]
+fn replay_long_read() {
+    use crate::memory::MemoryFileManager;
+
+    let mut trace = MAGIC.to_vec();
+    let calls = [
+        (
+            Call::Open {
+                path: PathId::from("/file"),
+                options: OpenFlags {
+                    read: true,
+                    write: true,
+                    create: true,
+                },
+                handle: 0,
+            },
+            Outcome::Unit,
+        ),
+        (
+            Call::Write {
+                handle: 0,
+                data: b"hello".to_vec(),
+            },
+            Outcome::Count(5),
+        ),
+        (
+            Call::Seek {
+                handle: 0,
+                pos: SeekFrom::Start(0),
+            },
+            Outcome::Count(0),
+        ),
+        // A corrupt trace can contain any length.
+        (
+            Call::Read {
+                handle: 0,
+                length: u64::MAX,
+            },
+            Outcome::data(b"hello"),
+        ),
+    ];
+    for (call, outcome) in calls {
+        call.encode(&mut trace);
+        outcome.encode(&mut trace);
+    }
+
+    assert_eq!(
+        replay(trace.as_slice(), &MemoryFileManager::default()).unwrap(),
+        []
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn non_utf8_paths_round_trip() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    let path = PathId::from(Path::new(OsStr::from_bytes(b"/caf\xe9")));
+    let mut encoded = Vec::new();
+    encode_path(&path, &mut encoded);
+    assert_eq!(decode_path(&mut encoded.as_slice()).unwrap(), path);
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::{File, FileManager, OpenOptions, PathId};
+
+/// Copies the file or directory tree at `source_path` in `source` to
+/// `destination_path` in `destination`, returning the number of files copied.
+///
+/// The managers can be different [`FileManager`] implementations, such as
+/// copying a directory from disk into a
+/// [`MemoryFileManager`](crate::memory::MemoryFileManager). Missing parent
+/// directories of `destination_path` are created, and existing files are
+/// overwritten. Files and directories in the destination that don't exist in
+/// the source are left untouched.
+///
+/// Nothing is synced. Use [`File::sync_all()`] or
+/// [`FileManager::sync_all()`] if the copy needs to be durable.
+pub fn copy_tree<Source, Destination>(
+    source: &Source,
+    source_path: &PathId,
+    destination: &Destination,
+    destination_path: &PathId,
+) -> io::Result<usize>
+where
+    Source: FileManager,
+    Destination: FileManager,
+{
+    copy(source, source_path, destination, destination_path, false)
+}
+
+/// Copies the file or directory tree at `source_path` in `source` to
+/// `destination_path` in `destination`, skipping files whose destination
+/// already has the same contents. Returns the number of files copied.
+///
+/// A file is skipped when the destination file has the same length and the
+/// same bytes, which requires reading both files. Otherwise, this behaves like
+/// [`copy_tree()`].
+pub fn copy_tree_incremental<Source, Destination>(
+    source: &Source,
+    source_path: &PathId,
+    destination: &Destination,
+    destination_path: &PathId,
+) -> io::Result<usize>
+where
+    Source: FileManager,
+    Destination: FileManager,
+{
+    copy(source, source_path, destination, destination_path, true)
+}
+
+fn copy<Source, Destination>(
+    source: &Source,
+    source_path: &PathId,
+    destination: &Destination,
+    destination_path: &PathId,
+    incremental: bool,
+) -> io::Result<usize>
+where
+    Source: FileManager,
+    Destination: FileManager,
+{
+    if let Some(parent) = destination_path.parent() {
+        destination.create_dir_all(&parent)?;
+    }
+    copy_entry(
+        source,
+        source_path,
+        destination,
+        destination_path,
+        incremental,
+    )
+}
+
+fn copy_entry<Source, Destination>(
+    source: &Source,
+    source_path: &PathId,
+    destination: &Destination,
+    destination_path: &PathId,
+    incremental: bool,
+) -> io::Result<usize>
+where
+    Source: FileManager,
+    Destination: FileManager,
+{
+    match source.list(source_path) {
+        Ok(entries) => {
+            destination.create_dir_all(destination_path)?;
+            let mut copied = 0;
+            for entry in entries {
+                let name = entry.file_name().expect("only / has no file name");
+                copied += copy_entry(
+                    source,
+                    &entry,
+                    destination,
+                    &PathId::from(destination_path.join(name)),
+                    incremental,
+                )?;
+            }
+            Ok(copied)
+        }
+        // Listing a file fails, but the path exists.
+        Err(_) if source.exists(source_path) => {
+            let mut source_file = source.open(source_path, OpenOptions::new().read(true))?;
+            if incremental
+                && destination.exists(destination_path)
+                && same_contents(&mut source_file, destination, destination_path)?
+            {
+                return Ok(0);
+            }
+
+            let mut destination_file = destination.open(
+                destination_path,
+                OpenOptions::new().write(true).create(true),
+            )?;
+            source_file.seek(SeekFrom::Start(0))?;
+            let length = io::copy(&mut source_file, &mut destination_file)?;
+            // Opening an existing file doesn't truncate it.
+            destination_file.set_len(length)?;
+            Ok(1)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn same_contents<Destination: FileManager>(
+    source_file: &mut impl File,
+    destination: &Destination,
+    destination_path: &PathId,
+) -> io::Result<bool> {
+    let mut destination_file = destination.open(destination_path, OpenOptions::new().read(true))?;
+    Ok(source_file.len()? == destination_file.len()?
+        && first_difference(source_file, &mut destination_file)?.is_none())
+}
+
+/// Returns the offset of the first byte that differs between `a` and `b`. If
+/// one is a prefix of the other, the difference is at the end of the shorter
+/// one.
+fn first_difference(a: &mut impl Read, b: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut a_buffer = vec![0; 8192];
+    let mut b_buffer = vec![0; 8192];
+    let mut offset = 0;
+    loop {
+        let a_read = read_up_to(a, &mut a_buffer)?;
+        let b_read = read_up_to(b, &mut b_buffer)?;
+        let common = a_read.min(b_read);
+        if let Some(index) = a_buffer[..common]
+            .iter()
+            .zip(&b_buffer[..common])
+            .position(|(a, b)| a != b)
+        {
+            return Ok(Some(offset + index as u64));
+        } else if a_read != b_read {
+            return Ok(Some(offset + common as u64));
+        } else if a_read == 0 {
+            return Ok(None);
+        }
+        offset += a_read as u64;
+    }
+}
+
+/// Fills as much of `buffer` as possible, only returning less than its length
+/// at the end of the file.
+fn read_up_to(file: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Compares the file or directory tree at `left_path` in `left` with the tree
+/// at `right_path` in `right`.
+///
+/// The managers can be different [`FileManager`] implementations, which allows
+/// checking that running the same workload against each of them produced the
+/// same result. Files are compared by reading their contents.
+pub fn diff_trees<Left, Right>(
+    left: &Left,
+    left_path: &PathId,
+    right: &Right,
+    right_path: &PathId,
+) -> io::Result<TreeDiff>
+where
+    Left: FileManager,
+    Right: FileManager,
+{
+    let mut diff = TreeDiff::default();
+    diff_entry(
+        left,
+        left_path,
+        right,
+        right_path,
+        &mut PathBuf::new(),
+        &mut diff,
+    )?;
+    diff.added.sort_unstable();
+    diff.removed.sort_unstable();
+    diff.modified.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    Ok(diff)
+}
+
+fn diff_entry<Left, Right>(
+    left: &Left,
+    left_path: &PathId,
+    right: &Right,
+    right_path: &PathId,
+    relative: &mut PathBuf,
+    diff: &mut TreeDiff,
+) -> io::Result<()>
+where
+    Left: FileManager,
+    Right: FileManager,
+{
+    match (entry_kind(left, left_path)?, entry_kind(right, right_path)?) {
+        (EntryKind::Directory(left_entries), EntryKind::Directory(right_entries)) => {
+            let mut right_entries = right_entries
+                .into_iter()
+                .map(|entry| {
+                    (
+                        entry
+                            .file_name()
+                            .expect("only / has no file name")
+                            .to_os_string(),
+                        entry,
+                    )
+                })
+                .collect::<HashMap<_, _>>();
+            for left_entry in left_entries {
+                let name = left_entry.file_name().expect("only / has no file name");
+                relative.push(name);
+                if let Some(right_entry) = right_entries.remove(name) {
+                    diff_entry(left, &left_entry, right, &right_entry, relative, diff)?;
+                } else {
+                    diff.removed.push(relative.clone());
+                }
+                relative.pop();
+            }
+            diff.added
+                .extend(right_entries.into_keys().map(|name| relative.join(name)));
+        }
+        (EntryKind::File, EntryKind::File) => {
+            let mut left_file = left.open(left_path, OpenOptions::new().read(true))?;
+            let mut right_file = right.open(right_path, OpenOptions::new().read(true))?;
+            if let Some(offset) = first_difference(&mut left_file, &mut right_file)? {
+                diff.modified.push(ModifiedFile {
+                    path: relative.clone(),
+                    first_difference: offset,
+                });
+            }
+        }
+        // The entry was replaced by an entry of a different kind.
+        _ => {
+            diff.removed.push(relative.clone());
+            diff.added.push(relative.clone());
+        }
+    }
+    Ok(())
+}
+
+enum EntryKind {
+    Directory(Vec<PathId>),
+    File,
+}
+
+fn entry_kind<Manager: FileManager>(manager: &Manager, path: &PathId) -> io::Result<EntryKind> {
+    match manager.list(path) {
+        Ok(entries) => Ok(EntryKind::Directory(entries)),
+        // Listing a file fails, but the path exists.
+        Err(_) if manager.exists(path) => Ok(EntryKind::File),
+        Err(err) => Err(err),
+    }
+}
+
+/// The differences between two trees, as returned by [`diff_trees()`].
+///
+/// All paths are relative to the compared paths, and each list is sorted.
+/// When a directory is added or removed, only the directory itself is listed.
+/// An entry that was replaced by an entry of a different kind, such as a file
+/// replaced by a directory, is listed as both removed and added.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct TreeDiff {
+    /// Entries that only exist in the right tree.
+    pub added: Vec<PathBuf>,
+    /// Entries that only exist in the left tree.
+    pub removed: Vec<PathBuf>,
+    /// Files that exist in both trees with different contents.
+    pub modified: Vec<ModifiedFile>,
+}
+
+impl TreeDiff {
+    /// Returns true if both trees are identical.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl Display for TreeDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for path in &self.added {
+            writeln!(f, "added: {}", path.display())?;
+        }
+        for path in &self.removed {
+            writeln!(f, "removed: {}", path.display())?;
+        }
+        for file in &self.modified {
+            writeln!(
+                f,
+                "modified: {} (first difference at offset {})",
+                file.path.display(),
+                file.first_difference
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A file whose contents differ between two trees.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModifiedFile {
+    /// The path of the file, relative to the compared paths.
+    pub path: PathBuf,
+    /// The offset of the first byte that differs. If one file is a prefix of
+    /// the other, this is the length of the shorter file.
+    pub first_difference: u64,
+}
+
+/// Syncs the file or directory tree at `path`, children before their parents.
+pub(crate) fn sync_tree<Manager: FileManager>(manager: &Manager, path: &PathId) -> io::Result<()> {
+    if let Ok(entries) = manager.list(path) {
+        for entry in entries {
+            sync_tree(manager, &entry)?;
+        }
+    }
+    manager.sync_all(path)
+}